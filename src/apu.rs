@@ -1,18 +1,16 @@
 use std::{
-    borrow::{Borrow, BorrowMut},
     cell::RefCell,
     ops::{BitAnd, Shl, Shr, ShrAssign},
     rc::Rc,
 };
 
-use sdl2::{
-    audio::{AudioQueue, AudioSpecDesired},
-    AudioSubsystem,
-};
-
 use bitflags::bitflags;
 
-use crate::{ines::Cartridge, log_apu};
+use crate::{
+    ines::Cartridge,
+    log_apu,
+    save_state::{SaveStateError, Snapshot, StateReader, StateWriter},
+};
 
 /*
      |  0   1   2   3   4   5   6   7    8   9   A   B   C   D   E   F
@@ -93,6 +91,8 @@ impl Sweep {
 enum PulseType {
     Pulse1,
     Pulse2,
+    /// MMC5's pulses have no sweep unit, and low periods aren't silenced.
+    Mmc5,
 }
 
 struct PulseChannel {
@@ -184,8 +184,10 @@ impl PulseChannel {
     }
 
     fn get_current_volume(&self) -> u8 {
-        if self.timer < 8
-            || (!self.sweep.negate && self.next_target_period() > 0x7ff)
+        let sweep_mutes = self.pulse_type != PulseType::Mmc5
+            && (self.timer < 8 || (!self.sweep.negate && self.next_target_period() > 0x7ff));
+
+        if sweep_mutes
             || self.length == 0
             || DUTIES[self.envelope.duty as usize] & (1 << self.current_duty) == 0
         {
@@ -623,9 +625,38 @@ impl FrameCounter {
     }
 }
 
+/// Destination for the mixed APU output, queued in chunks of mono 44.1 kHz samples.
+pub trait AudioSink {
+    fn queue(&mut self, samples: &[f32]);
+}
+
+/// Keeps every queued sample in memory, for running without an audio device.
+/// Clones share the same buffer, so the caller can hold one and drain it.
+#[derive(Clone, Default)]
+pub struct MemoryAudioSink {
+    samples: Rc<RefCell<Vec<f32>>>,
+}
+
+impl MemoryAudioSink {
+    pub fn new() -> MemoryAudioSink {
+        MemoryAudioSink::default()
+    }
+
+    pub fn take_samples(&self) -> Vec<f32> {
+        self.samples.replace(Vec::new())
+    }
+}
+
+impl AudioSink for MemoryAudioSink {
+    fn queue(&mut self, samples: &[f32]) {
+        self.samples.borrow_mut().extend_from_slice(samples);
+    }
+}
+
 const DMC_RATE_TABLE: [u16; 16] = [
     428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
 ];
+
 struct DmcChannel {
     sample_buffer: Option<u8>,
     rate: u16,
@@ -643,8 +674,7 @@ struct DmcChannel {
 
     loops_playback: bool,
     irq_enabled: bool,
-
-    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    interrupt_flag: bool,
 }
 
 impl DmcChannel {
@@ -662,7 +692,7 @@ impl DmcChannel {
             sample_length: 0,
             loops_playback: false,
             irq_enabled: false,
-            cartridge: None,
+            interrupt_flag: false,
             current_address: 0,
         }
     }
@@ -698,28 +728,34 @@ impl DmcChannel {
                 self.bits_left -= 1;
                 self.shift_register >>= 1;
             }
+        }
+    }
 
-            if self.current_length > 0 && self.sample_buffer == None {
-                match self.cartridge.as_mut() {
-                    Some(cartridge) => {
-                        let cartridge = cartridge.as_ref();
-                        let value = cartridge.borrow_mut().read_address(self.current_address) & 127;
-                        self.sample_buffer = Some(value);
-                    }
-                    _ => {
-                        todo!()
-                    }
-                }
+    // The CPU fetches the next sample byte for us whenever the buffer is empty.
+    fn dma_address(&self) -> Option<u16> {
+        if self.current_length > 0 && self.sample_buffer.is_none() {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
 
-                self.current_address = self.current_address.wrapping_add(1) | 0x8000;
-                self.current_length -= 1;
+    fn finish_dma(&mut self, value: u8) {
+        // The channel may have been turned off while the CPU was fetching.
+        if self.dma_address().is_none() {
+            return;
+        }
 
-                if self.current_length == 0 {
-                    if self.loops_playback {
-                        self.current_length = self.sample_length;
-                        self.current_address = self.sample_address;
-                    }
-                } // todo: handle IRQ
+        self.sample_buffer = Some(value & 127);
+        self.current_address = self.current_address.wrapping_add(1) | 0x8000;
+        self.current_length -= 1;
+
+        if self.current_length == 0 {
+            if self.loops_playback {
+                self.current_length = self.sample_length;
+                self.current_address = self.sample_address;
+            } else if self.irq_enabled {
+                self.interrupt_flag = true;
             }
         }
     }
@@ -739,9 +775,15 @@ impl DmcChannel {
         self.rate = DMC_RATE_TABLE[rate_index as usize] / 2;
         self.loops_playback = value & 0b01000000 != 0;
         self.irq_enabled = value & 0b10000000 != 0;
+
+        if !self.irq_enabled {
+            self.interrupt_flag = false;
+        }
     }
 
     fn set_enabled(&mut self, enabled: bool) {
+        self.interrupt_flag = false;
+
         if enabled {
             if self.current_length == 0 {
                 self.current_length = self.sample_length;
@@ -752,15 +794,323 @@ impl DmcChannel {
         }
     }
 
-    fn set_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
-        self.cartridge = Some(cartridge);
-    }
-
     fn get_current_volume(&self) -> u8 {
         self.current_output
     }
 }
 
+/// Sound chips found on cartridges.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExpansionChip {
+    Mmc5,
+    Vrc6,
+}
+
+impl ExpansionChip {
+    /// How loud the chip is at full scale, in APU pulse channels at full volume.
+    fn relative_volume(self) -> f32 {
+        match self {
+            ExpansionChip::Mmc5 => MMC5_FULL_SCALE / 15.0,
+            ExpansionChip::Vrc6 => VRC6_FULL_SCALE / 15.0,
+        }
+    }
+}
+
+/// A sound chip on the cartridge, mixed into the APU output.
+pub trait ExpansionAudio {
+    fn chip(&self) -> ExpansionChip;
+
+    /// Advances the chip by one CPU cycle and returns its output, from 0.0 to 1.0.
+    fn step(&mut self) -> f32;
+}
+
+// MMC5 clocks its envelopes and length counters at a fixed 240 Hz,
+// independently of the APU frame counter.
+const MMC5_FRAME_PERIOD: u32 = 7457;
+
+const MMC5_FULL_SCALE: f32 = 30.0 + 255.0 / 4.0;
+
+/// The audio part of MMC5: two pulse channels without sweep, and a raw 8-bit PCM channel.
+/// The reading mode of the PCM channel, which fetches samples from PRG ROM, is not supported.
+pub struct Mmc5Audio {
+    pulse1_channel: PulseChannel,
+    pulse2_channel: PulseChannel,
+    pcm: u8,
+    pcm_read_mode: bool,
+    frame_cycles: u32,
+    odd_cycle: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Mmc5Audio {
+        Mmc5Audio {
+            pulse1_channel: PulseChannel::new(PulseType::Mmc5),
+            pulse2_channel: PulseChannel::new(PulseType::Mmc5),
+            pcm: 0,
+            pcm_read_mode: false,
+            frame_cycles: 0,
+            odd_cycle: false,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000 => self.pulse1_channel.set_envelope_flag(value),
+            0x5002 => self.pulse1_channel.set_low_timer(value),
+            0x5003 => self.pulse1_channel.set_length_counter_and_high_timer(value),
+            0x5004 => self.pulse2_channel.set_envelope_flag(value),
+            0x5006 => self.pulse2_channel.set_low_timer(value),
+            0x5007 => self.pulse2_channel.set_length_counter_and_high_timer(value),
+            0x5010 => self.pcm_read_mode = value & 1 != 0,
+            // Zero can't be written, it's used to mark the end of a sample.
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1_channel.set_enabled(value & 1 != 0);
+                self.pulse2_channel.set_enabled(value & 2 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_status(&self) -> u8 {
+        self.pulse1_channel.is_running() as u8 | (self.pulse2_channel.is_running() as u8) << 1
+    }
+
+    // In steps of an APU pulse channel's volume. The PCM channel at full scale is
+    // about as loud as four pulse channels.
+    fn output(&self) -> f32 {
+        let pulse =
+            self.pulse1_channel.get_current_volume() + self.pulse2_channel.get_current_volume();
+        let pcm = self.pcm as f32 / 4.0;
+
+        (pulse as f32 + pcm) / MMC5_FULL_SCALE
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Mmc5
+    }
+
+    fn step(&mut self) -> f32 {
+        if self.odd_cycle {
+            self.pulse1_channel.step();
+            self.pulse2_channel.step();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.frame_cycles += 1;
+        if self.frame_cycles == MMC5_FRAME_PERIOD {
+            self.frame_cycles = 0;
+
+            for channel in [&mut self.pulse1_channel, &mut self.pulse2_channel].iter_mut() {
+                channel.quarter_frame_clock();
+                channel.length_step();
+            }
+        }
+
+        self.output()
+    }
+}
+
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    ignore_duty: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    duty_step: u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            ignore_duty: false,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            duty_step: 0,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = value & 0x80 != 0;
+                self.duty = (value >> 4) & 7;
+                self.volume = value & 0xf;
+            }
+            1 => self.period = (self.period & 0xf00) | value as u16,
+            2 => {
+                self.period = (self.period & 0xff) | (value as u16 & 0xf) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.duty_step = 15;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> period_shift;
+            self.duty_step = self.duty_step.wrapping_sub(1) & 15;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.duty_step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct Vrc6Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Sawtooth {
+    fn new() -> Vrc6Sawtooth {
+        Vrc6Sawtooth {
+            rate: 0,
+            period: 0,
+            enabled: false,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3f,
+            1 => self.period = (self.period & 0xf00) | value as u16,
+            2 => {
+                self.period = (self.period & 0xff) | (value as u16 & 0xf) << 8;
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The rate is added on every second clock, and the seventh addition resets it instead.
+    fn step(&mut self, period_shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.period >> period_shift;
+        self.step += 1;
+
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// Every volume step is about as loud as one of an APU pulse channel.
+const VRC6_FULL_SCALE: f32 = 15.0 + 15.0 + 31.0;
+
+/// The audio part of VRC6: two pulse channels with 8 duty cycles and a sawtooth channel.
+/// Registers are addressed as $9000-$B002 after the board's address line swap.
+pub struct Vrc6Audio {
+    pulse1_channel: Vrc6Pulse,
+    pulse2_channel: Vrc6Pulse,
+    sawtooth_channel: Vrc6Sawtooth,
+    halted: bool,
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Vrc6Audio {
+        Vrc6Audio {
+            pulse1_channel: Vrc6Pulse::new(),
+            pulse2_channel: Vrc6Pulse::new(),
+            sawtooth_channel: Vrc6Sawtooth::new(),
+            halted: false,
+            period_shift: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        let register = address & 3;
+
+        match address & 0xf000 {
+            0x9000 if register == 3 => {
+                self.halted = value & 1 != 0;
+                self.period_shift = if value & 4 != 0 {
+                    8
+                } else if value & 2 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000 => self.pulse1_channel.write_register(register, value),
+            0xa000 => self.pulse2_channel.write_register(register, value),
+            0xb000 => self.sawtooth_channel.write_register(register, value),
+            _ => {}
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let sum = self.pulse1_channel.output()
+            + self.pulse2_channel.output()
+            + self.sawtooth_channel.output();
+
+        sum as f32 / VRC6_FULL_SCALE
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn chip(&self) -> ExpansionChip {
+        ExpansionChip::Vrc6
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.halted {
+            self.pulse1_channel.step(self.period_shift);
+            self.pulse2_channel.step(self.period_shift);
+            self.sawtooth_channel.step(self.period_shift);
+        }
+
+        self.output()
+    }
+}
+
 pub struct Apu {
     half_cycle_count: usize,
     pulse1_channel: PulseChannel,
@@ -770,27 +1120,19 @@ pub struct Apu {
     dmc_channel: DmcChannel,
     tnd_table: [f32; 203],
     pulse_table: [f32; 31],
-    output_queue: AudioQueue<f32>,
+    output: Box<dyn AudioSink>,
 
     buffer: [f32; 2048],
     buffer_index: usize,
     next_fill: usize,
     has_extra: bool,
     frame_counter: FrameCounter,
+    cartridge: Option<Rc<RefCell<Cartridge>>>,
+    expansion_out: f32,
 }
 
 impl Apu {
-    pub fn new(audio_subsystem: AudioSubsystem) -> Apu {
-        let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
-            channels: Some(1),   // mono
-            samples: Some(2048), // default sample size
-        };
-
-        let output_queue =
-            audio_subsystem.open_queue(None, &desired_spec).unwrap() as AudioQueue<f32>;
-        output_queue.resume();
-
+    pub fn new(output: Box<dyn AudioSink>) -> Apu {
         Apu {
             half_cycle_count: 0,
             pulse1_channel: PulseChannel::new(PulseType::Pulse1),
@@ -799,13 +1141,15 @@ impl Apu {
             noise_channel: NoiseChannel::new(),
             tnd_table: create_tnd_table(),
             pulse_table: create_pulse_table(),
-            output_queue,
+            output,
             buffer: [0.0; 2048],
             buffer_index: 0,
             next_fill: 40,
             has_extra: true,
             frame_counter: FrameCounter::new(),
             dmc_channel: DmcChannel::new(),
+            cartridge: None,
+            expansion_out: 0.0,
         }
     }
 
@@ -833,6 +1177,13 @@ impl Apu {
             self.dmc_channel.step();
         }
 
+        if let Some(cartridge) = &self.cartridge {
+            if let Some(audio) = cartridge.borrow_mut().expansion_audio() {
+                let sample = audio.step() * audio.chip().relative_volume();
+                self.expansion_out = sample * self.pulse_table[15];
+            }
+        }
+
         if self.half_cycle_count % self.next_fill == 0 {
             self.next_fill += 40 + self.has_extra as usize;
             self.has_extra = !self.has_extra;
@@ -855,14 +1206,14 @@ impl Apu {
 
             let pulse_out = self.pulse_table[pulse1 + pulse2];
 
-            let output = tnd_out + pulse_out;
+            let output = tnd_out + pulse_out + self.expansion_out;
 
             self.buffer[self.buffer_index] = output;
 
             self.buffer_index += 1;
             if self.buffer_index == 2048 {
                 self.buffer_index = 0;
-                self.output_queue.queue(&self.buffer);
+                self.output.queue(&self.buffer);
             }
         }
 
@@ -974,6 +1325,7 @@ impl Apu {
         status.set(ApuStatus::TRIANGLE, self.triangle_channel.is_running());
         status.set(ApuStatus::NOISE, self.noise_channel.is_running());
         status.set(ApuStatus::DMC, self.dmc_channel.is_running());
+        status.set(ApuStatus::DMC_INTERRUPT, self.dmc_channel.interrupt_flag);
 
         let bits = status.bits();
         log_apu!("Read $4015: {:#010b}", bits);
@@ -1010,11 +1362,339 @@ impl Apu {
     }
 
     pub fn set_cartridge(&mut self, cartridge: Rc<RefCell<Cartridge>>) {
-        self.dmc_channel.set_cartridge(cartridge);
+        self.cartridge = Some(cartridge);
     }
 
     pub fn has_pending_irq(&self) -> bool {
-        self.frame_counter.has_pending_irq()
+        self.frame_counter.has_pending_irq() || self.dmc_channel.interrupt_flag
+    }
+
+    /// The address of the sample byte the DMC is waiting for, if it's waiting for one.
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc_channel.dma_address()
+    }
+
+    /// Hands the DMC the sample byte it was waiting for.
+    pub fn finish_dmc_dma(&mut self, value: u8) {
+        self.dmc_channel.finish_dma(value);
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.duty);
+        writer.write_u8(self.volume);
+        writer.write_bool(self.loops_playback);
+        writer.write_bool(self.constant_volume);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.duty = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        self.loops_playback = reader.read_bool()?;
+        self.constant_volume = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.period);
+        writer.write_u8(self.shift);
+        writer.write_bool(self.negate);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.shift = reader.read_u8()?;
+        self.negate = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for PulseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.envelope.save_state(writer);
+        self.sweep.save_state(writer);
+
+        writer.write_u16(self.timer);
+        writer.write_u16(self.current_timer);
+        writer.write_u8(self.length);
+
+        writer.write_u8(self.current_duty);
+
+        writer.write_u8(self.envelope_clock);
+        writer.write_bool(self.restart_envelope);
+        writer.write_u8(self.sweep_clock);
+        writer.write_bool(self.restart_sweep);
+        writer.write_u8(self.current_volume);
+
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.envelope.load_state(reader)?;
+        self.sweep.load_state(reader)?;
+
+        self.timer = reader.read_u16()?;
+        self.current_timer = reader.read_u16()?;
+        self.length = reader.read_u8()?;
+
+        self.current_duty = reader.read_u8()?;
+
+        self.envelope_clock = reader.read_u8()?;
+        self.restart_envelope = reader.read_bool()?;
+        self.sweep_clock = reader.read_u8()?;
+        self.restart_sweep = reader.read_bool()?;
+        self.current_volume = reader.read_u8()?;
+
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Mmc5Audio {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse1_channel.save_state(writer);
+        self.pulse2_channel.save_state(writer);
+        writer.write_u8(self.pcm);
+        writer.write_bool(self.pcm_read_mode);
+        writer.write_u32(self.frame_cycles);
+        writer.write_bool(self.odd_cycle);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.pulse1_channel.load_state(reader)?;
+        self.pulse2_channel.load_state(reader)?;
+        self.pcm = reader.read_u8()?;
+        self.pcm_read_mode = reader.read_bool()?;
+        self.frame_cycles = reader.read_u32()?;
+        self.odd_cycle = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Vrc6Pulse {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.volume);
+        writer.write_u8(self.duty);
+        writer.write_bool(self.ignore_duty);
+        writer.write_u16(self.period);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.volume = reader.read_u8()?;
+        self.duty = reader.read_u8()?;
+        self.ignore_duty = reader.read_bool()?;
+        self.period = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u16()?;
+        self.duty_step = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Vrc6Sawtooth {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.rate);
+        writer.write_u16(self.period);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.step);
+        writer.write_u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.rate = reader.read_u8()?;
+        self.period = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u16()?;
+        self.step = reader.read_u8()?;
+        self.accumulator = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for Vrc6Audio {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse1_channel.save_state(writer);
+        self.pulse2_channel.save_state(writer);
+        self.sawtooth_channel.save_state(writer);
+        writer.write_bool(self.halted);
+        writer.write_u8(self.period_shift);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.pulse1_channel.load_state(reader)?;
+        self.pulse2_channel.load_state(reader)?;
+        self.sawtooth_channel.load_state(reader)?;
+        self.halted = reader.read_bool()?;
+        self.period_shift = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for TriangleChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.timer);
+        writer.write_u16(self.current_timer);
+
+        writer.write_u8(self.length);
+        writer.write_u8(self.current_linear_counter);
+        writer.write_u8(self.linear_counter);
+        writer.write_bool(self.linear_counter_reload);
+
+        writer.write_bool(self.control_flag);
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.tri_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.timer = reader.read_u16()?;
+        self.current_timer = reader.read_u16()?;
+
+        self.length = reader.read_u8()?;
+        self.current_linear_counter = reader.read_u8()?;
+        self.linear_counter = reader.read_u8()?;
+        self.linear_counter_reload = reader.read_bool()?;
+
+        self.control_flag = reader.read_bool()?;
+        self.enabled = reader.read_bool()?;
+        self.tri_step = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.shift_register);
+        writer.write_bool(self.mode_flag);
+        writer.write_u16(self.noise_period);
+        writer.write_u16(self.current_noise_timer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.envelope_clock);
+        writer.write_u8(self.current_volume);
+
+        writer.write_u8(self.length);
+        writer.write_bool(self.restart_envelope);
+
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift_register = reader.read_u16()?;
+        self.mode_flag = reader.read_bool()?;
+        self.noise_period = reader.read_u16()?;
+        self.current_noise_timer = reader.read_u16()?;
+        self.envelope.load_state(reader)?;
+        self.envelope_clock = reader.read_u8()?;
+        self.current_volume = reader.read_u8()?;
+
+        self.length = reader.read_u8()?;
+        self.restart_envelope = reader.read_bool()?;
+
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for FrameCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.cpu_cycles);
+        writer.write_bool(self.reset);
+        writer.write_bool(self.mode_flag);
+        writer.write_bool(self.irq_inhibit_flag);
+        writer.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cpu_cycles = reader.read_usize()?;
+        self.reset = reader.read_bool()?;
+        self.mode_flag = reader.read_bool()?;
+        self.irq_inhibit_flag = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for DmcChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.sample_buffer.is_some());
+        writer.write_u8(self.sample_buffer.unwrap_or(0));
+        writer.write_u16(self.rate);
+        writer.write_u16(self.current_timer);
+
+        writer.write_bool(self.silence);
+        writer.write_u8(self.bits_left);
+        writer.write_u8(self.shift_register);
+
+        writer.write_u8(self.current_output);
+        writer.write_u16(self.sample_address);
+        writer.write_u16(self.current_address);
+        writer.write_u16(self.sample_length);
+        writer.write_u16(self.current_length);
+
+        writer.write_bool(self.loops_playback);
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.interrupt_flag);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let has_sample = reader.read_bool()?;
+        let sample = reader.read_u8()?;
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+        self.rate = reader.read_u16()?;
+        self.current_timer = reader.read_u16()?;
+
+        self.silence = reader.read_bool()?;
+        self.bits_left = reader.read_u8()?;
+        self.shift_register = reader.read_u8()?;
+
+        self.current_output = reader.read_u8()?;
+        self.sample_address = reader.read_u16()?;
+        self.current_address = reader.read_u16()?;
+        self.sample_length = reader.read_u16()?;
+        self.current_length = reader.read_u16()?;
+
+        self.loops_playback = reader.read_bool()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.interrupt_flag = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+// The output buffer is not part of the state, a restored machine keeps
+// filling the buffer it already has.
+impl Snapshot for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.half_cycle_count);
+        self.pulse1_channel.save_state(writer);
+        self.pulse2_channel.save_state(writer);
+        self.triangle_channel.save_state(writer);
+        self.noise_channel.save_state(writer);
+        self.dmc_channel.save_state(writer);
+
+        writer.write_usize(self.next_fill);
+        writer.write_bool(self.has_extra);
+        self.frame_counter.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.half_cycle_count = reader.read_usize()?;
+        self.pulse1_channel.load_state(reader)?;
+        self.pulse2_channel.load_state(reader)?;
+        self.triangle_channel.load_state(reader)?;
+        self.noise_channel.load_state(reader)?;
+        self.dmc_channel.load_state(reader)?;
+
+        self.next_fill = reader.read_usize()?;
+        self.has_extra = reader.read_bool()?;
+        self.frame_counter.load_state(reader)
     }
 }
 
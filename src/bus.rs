@@ -6,6 +6,7 @@ use crate::{
     ines::Cartridge,
     log_ppu,
     ppu::{Ppu, PpuControl, PpuMask},
+    save_state::{SaveStateError, Snapshot, StateReader, StateWriter},
 };
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -27,20 +28,201 @@ pub enum JoypadButton {
     Right,
 }
 
+const JOYPAD_BUTTONS: [JoypadButton; 8] = [
+    JoypadButton::A,
+    JoypadButton::B,
+    JoypadButton::Select,
+    JoypadButton::Start,
+    JoypadButton::Up,
+    JoypadButton::Down,
+    JoypadButton::Left,
+    JoypadButton::Right,
+];
+
+impl Snapshot for JoypadState {
+    fn save_state(&self, writer: &mut StateWriter) {
+        match self {
+            JoypadState::Idle => writer.write_u8(0),
+            JoypadState::Polling => writer.write_u8(1),
+            JoypadState::Ready(button) => {
+                let index = JOYPAD_BUTTONS.iter().position(|b| b == button).unwrap();
+                writer.write_u8(2 + index as u8);
+            }
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        *self = match reader.read_u8()? {
+            0 => JoypadState::Idle,
+            1 => JoypadState::Polling,
+            value @ 2..=9 => JoypadState::Ready(JOYPAD_BUTTONS[value as usize - 2]),
+            _ => return Err(SaveStateError::InvalidValue),
+        };
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum JoypadPort {
+    One,
+    Two,
+}
+
+/// A standard controller plugged into one of the ports, read serially through $4016/$4017.
+pub struct Joypad {
+    pub active_buttons: HashSet<JoypadButton>,
+    state: JoypadState,
+}
+
+impl Joypad {
+    fn new() -> Joypad {
+        Joypad {
+            active_buttons: HashSet::new(),
+            state: JoypadState::Idle,
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let value: u8 = match self.state {
+            JoypadState::Ready(button) => {
+                if self.active_buttons.contains(&button) {
+                    1
+                } else {
+                    0
+                }
+            }
+            JoypadState::Polling => 0,
+            JoypadState::Idle => 1,
+        };
+
+        let next_state = match self.state {
+            JoypadState::Ready(button) => match button {
+                JoypadButton::A => JoypadState::Ready(JoypadButton::B),
+                JoypadButton::B => JoypadState::Ready(JoypadButton::Select),
+                JoypadButton::Select => JoypadState::Ready(JoypadButton::Start),
+                JoypadButton::Start => JoypadState::Ready(JoypadButton::Up),
+                JoypadButton::Up => JoypadState::Ready(JoypadButton::Down),
+                JoypadButton::Down => JoypadState::Ready(JoypadButton::Left),
+                JoypadButton::Left => JoypadState::Ready(JoypadButton::Right),
+                JoypadButton::Right => JoypadState::Idle,
+            },
+            _ => self.state,
+        };
+
+        self.state = next_state;
+
+        value
+    }
+
+    fn write_strobe(&mut self, value: u8) {
+        match (self.state, value & 1) {
+            // On nestest, the value is 9 and 8 instead of 1 and 0, we take
+            (_, 1) => self.state = JoypadState::Polling,
+            (_, 0) => self.state = JoypadState::Ready(JoypadButton::A),
+            _ => println!("Unknown joypad combination: {:?}, {}", self.state, value),
+        }
+    }
+}
+
 pub trait BusTrait {
     fn read_address(&mut self, address: u16) -> u8;
+    /// Returns true if the write starts a sprite DMA from page `value`.
     fn write_address(&mut self, address: u16, value: u8) -> bool;
 }
 
-pub type MemoryBuffer = [u8; 0x10000];
+/// The console's 2 KiB of internal RAM, mirrored up to $1FFF.
+pub type MemoryBuffer = [u8; 0x800];
 
 pub struct RealBus {
-    pub memory: MemoryBuffer,
-    pub active_buttons: HashSet<JoypadButton>,
-    pub joypad_state: JoypadState,
+    pub ram: MemoryBuffer,
+    // Last value on the CPU data bus, which is what reads of unmapped addresses return.
+    open_bus: u8,
+    pub joypads: [Joypad; 2],
     pub ppu: Ppu,
     pub apu: Apu,
     pub cartridge: Rc<RefCell<Cartridge>>,
+    // CPU cycles since power-on.
+    cycle: u64,
+    frame_completed: bool,
+}
+
+impl RealBus {
+    pub fn new(cartridge: Rc<RefCell<Cartridge>>, apu: Apu) -> RealBus {
+        RealBus {
+            ram: [0; 0x800],
+            open_bus: 0,
+            joypads: [Joypad::new(), Joypad::new()],
+            ppu: Ppu::new(cartridge.clone()),
+            cartridge,
+            apu,
+            cycle: 0,
+            frame_completed: false,
+        }
+    }
+
+    /// Runs everything but the CPU for one CPU cycle. The CPU calls this for
+    /// every bus access it makes and every cycle it sits idle.
+    pub fn tick(&mut self) {
+        self.cycle += 1;
+        self.cartridge.borrow_mut().cpu_tick();
+        self.apu.half_step();
+
+        for _ in 0..3 {
+            if self.ppu.step() {
+                self.frame_completed = true;
+            }
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Whether the PPU finished a frame since the last call.
+    pub fn take_frame_completed(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_completed, false)
+    }
+
+    /// The level of the shared IRQ line, which the APU and the cartridge can both pull.
+    pub fn has_pending_irq(&self) -> bool {
+        self.apu.has_pending_irq() || self.cartridge.borrow().has_pending_irq()
+    }
+
+    pub fn joypad_mut(&mut self, port: JoypadPort) -> &mut Joypad {
+        match port {
+            JoypadPort::One => &mut self.joypads[0],
+            JoypadPort::Two => &mut self.joypads[1],
+        }
+    }
+}
+
+impl Snapshot for RealBus {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.open_bus);
+        writer.write_u64(self.cycle);
+        for joypad in self.joypads.iter() {
+            joypad.state.save_state(writer);
+        }
+
+        self.ppu.save_state(writer);
+        self.apu.save_state(writer);
+        self.cartridge.borrow().save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.ram)?;
+        self.open_bus = reader.read_u8()?;
+        self.cycle = reader.read_u64()?;
+        for joypad in self.joypads.iter_mut() {
+            joypad.state.load_state(reader)?;
+        }
+
+        self.ppu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.cartridge.borrow_mut().load_state(reader)
+    }
 }
 
 fn unmirror(address: u16) -> u16 {
@@ -56,55 +238,50 @@ fn unmirror(address: u16) -> u16 {
 impl BusTrait for RealBus {
     fn read_address(&mut self, address: u16) -> u8 {
         let address = unmirror(address);
-        match address {
+        let value = match address {
+            0x0000..=0x07ff => self.ram[address as usize],
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
-            // 0x4017 => { this is used for controller 2 if i'm not mistaken
-            //     println!("APU Read {:#06X}", address);
-            //     self.memory[address as usize]
-            // }
-            0x4015 => self.apu.read_status(),
-            0x4016 => {
-                let value: u8 = match self.joypad_state {
-                    JoypadState::Ready(button) => {
-                        if self.active_buttons.contains(&button) {
-                            0x41
-                        } else {
-                            0x40
-                        }
-                    }
-                    JoypadState::Polling => 0x40,
-                    JoypadState::Idle => 0x41,
-                };
-
-                let next_state = match self.joypad_state {
-                    JoypadState::Ready(button) => match button {
-                        JoypadButton::A => JoypadState::Ready(JoypadButton::B),
-                        JoypadButton::B => JoypadState::Ready(JoypadButton::Select),
-                        JoypadButton::Select => JoypadState::Ready(JoypadButton::Start),
-                        JoypadButton::Start => JoypadState::Ready(JoypadButton::Up),
-                        JoypadButton::Up => JoypadState::Ready(JoypadButton::Down),
-                        JoypadButton::Down => JoypadState::Ready(JoypadButton::Left),
-                        JoypadButton::Left => JoypadState::Ready(JoypadButton::Right),
-                        JoypadButton::Right => JoypadState::Idle,
-                    },
-                    _ => self.joypad_state,
-                };
-
-                self.joypad_state = next_state;
-
-                return value;
-            }
+            0x2000..=0x2007 => self.ppu.read_io_latch(),
+            // $4015 is inside the CPU, so reading it leaves the external data bus alone
+            // and bit 5 isn't driven.
+            0x4015 => return self.apu.read_status() | (self.open_bus & 0x20),
+            // Controllers only drive the low bits.
+            0x4016 => self.joypads[0].read() | (self.open_bus & 0xe0),
+            0x4017 => self.joypads[1].read() | (self.open_bus & 0xe0),
+            0x4020..=0x5fff => self
+                .cartridge
+                .borrow_mut()
+                .read_expansion(address)
+                .unwrap_or(self.open_bus),
+            0x6000..=0x7fff => self
+                .cartridge
+                .borrow_mut()
+                .read_prg_ram(address)
+                .unwrap_or(self.open_bus),
             0x8000..=0xffff => self.cartridge.borrow_mut().read_address(address),
-            _ => self.memory[address as usize],
-        }
+            _ => self.open_bus,
+        };
+
+        self.open_bus = value;
+        value
     }
 
     #[must_use]
     fn write_address(&mut self, address: u16, value: u8) -> bool {
         let address = unmirror(address);
+        self.open_bus = value;
+
+        if let 0x2000..=0x2007 = address {
+            self.ppu.write_io_latch(value);
+            self.cartridge
+                .borrow_mut()
+                .ppu_register_written(address, value);
+        }
+
         match address {
+            0x0000..=0x07ff => self.ram[address as usize] = value,
             0x2000 => {
                 self.ppu.set_control(PpuControl::from_bits(value).unwrap());
             }
@@ -138,25 +315,54 @@ impl BusTrait for RealBus {
             0x4017 => self.apu.write_frame_counter(value),
             0x4014 => {
                 log_ppu!("Write $4014: {:#04X}", value);
-                let starting_address = value as usize * 0x100;
-                let slice = &self.memory[starting_address..=starting_address + 0xff];
-                self.ppu.copy_oam_data(slice);
-
+                // The CPU does the copy, since it's halted while the DMA uses its bus.
                 return true;
             }
-            0x4016 => match (self.joypad_state, value & 1) {
-                // On nestest, the value is 9 and 8 instead of 1 and 0, we take
-                (_, 1) => self.joypad_state = JoypadState::Polling,
-                (_, 0) => self.joypad_state = JoypadState::Ready(JoypadButton::A),
-                _ => println!(
-                    "Unknown joypad combination: {:?}, {}",
-                    self.joypad_state, value
-                ),
-            },
+            0x4016 => {
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write_strobe(value);
+                }
+            }
+            0x4020..=0x5fff => self.cartridge.borrow_mut().write_expansion(address, value),
+            0x6000..=0x7fff => self.cartridge.borrow_mut().write_prg_ram(address, value),
             0x8000..=0xffff => self.cartridge.borrow_mut().write_address(address, value),
-            _ => self.memory[address as usize] = value,
+            _ => {}
         }
 
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{apu::MemoryAudioSink, ines::nrom_cartridge};
+
+    #[test]
+    fn unmapped_reads_return_open_bus() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0] = 0x42;
+        let cartridge = nrom_cartridge(&prg_rom, &[0; 0x2000]);
+        let mut bus = RealBus::new(
+            Rc::new(RefCell::new(cartridge)),
+            Apu::new(Box::new(MemoryAudioSink::new())),
+        );
+
+        assert!(!bus.write_address(0x0801, 0x5a));
+        assert_eq!(bus.read_address(0x1801), 0x5a);
+        assert_eq!(bus.read_address(0x4018), 0x5a);
+
+        // Nothing stores writes to unmapped space, reads see the last bus value.
+        assert!(!bus.write_address(0x5000, 0x37));
+        assert_eq!(bus.read_address(0xc000), 0x42);
+        assert_eq!(bus.read_address(0x5000), 0x42);
+
+        // Only bit 0 comes from the controller.
+        assert!(!bus.write_address(0x4016, 0x40));
+        assert_eq!(bus.read_address(0x4016), 0x40);
+
+        // The PPU registers are mirrored all the way up, write-only ones read the I/O latch.
+        assert!(!bus.write_address(0x2003, 0xfe));
+        assert_eq!(bus.read_address(0x3ffd), 0xfe);
+    }
+}
@@ -2,1595 +2,802 @@ use std::ops::{BitAnd, BitOr};
 
 use crate::{
     bus::{BusTrait, MemoryBuffer, RealBus},
-    instruction::Instruction,
+    instruction::{AddressingMode, Instruction, Operation},
+    save_state::{SaveStateError, Snapshot, StateReader, StateWriter},
 };
 
-#[derive(Debug)]
-pub enum SideEffect {
-    WritePpuAddr(u8),
-    WritePpuData(u8),
-    WriteOamAddr(u8),
-    WriteOamData(u8),
-    OamDma(u8),
-    ClearAddressLatch,
-    SetPpuControl(u8),
-}
-
-pub struct CpuResult {
-    pub cycles_elapsed: u32,
-    pub side_effect: Option<SideEffect>,
-    pub has_dma: bool,
-}
+const NMI_VECTOR: u16 = 0xfffa;
+const IRQ_VECTOR: u16 = 0xfffe;
 
-#[inline(always)]
-fn cycles(cycles_elapsed: u32) -> CpuResult {
-    CpuResult {
-        cycles_elapsed: cycles_elapsed,
-        side_effect: None,
-        has_dma: false,
-    }
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Index {
+    X,
+    Y,
 }
 
-pub struct Cpu {
-    // memory: MemoryBuffer,
-    pc: u16,
-    a: u8,
-    x: u8,
-    y: u8,
-    p: u8,
-    sp: u8,
-
-    nmi_vector: u16,
-    reset_vector: u16,
-    irq_vector: u16,
-
-    pub bus: RealBus,
-    delayed_i_flag: Option<bool>,
+/// One cycle of an instruction after its opcode fetch. Each makes exactly one bus
+/// access, dummy accesses included, and does the work the CPU does alongside it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum MicroOp {
+    FetchImmediate,
+    FetchZeroPage,
+    FetchAddressLow,
+    FetchAddressHigh,
+    // Fetches the high byte and adds the index to the low byte, without the carry.
+    FetchAddressHighIndexed(Index),
+    // Reads the unindexed address while adding the index.
+    IndexZeroPage(Index),
+    FetchPointer,
+    // Reads the pointer while adding X to it.
+    IndexPointer,
+    FetchPointerLow,
+    FetchPointerHigh,
+    FetchPointerHighIndexed,
+    // Reads the indexed address before its high byte is fixed up. If no page was
+    // crossed that was the right address, and the instruction ends here.
+    ReadOrFixUp,
+    // The same read, done by writes and read-modify-writes whether a page was crossed or not.
+    FixUp,
+    ReadOperand,
+    WriteResult,
+    ReadForModify,
+    // The CPU writes the unmodified value back while it works out the new one, so
+    // registers with write side effects see two writes.
+    WriteUnmodified,
+    WriteModified,
+    // Single byte instructions read the byte after the opcode, then ignore it.
+    ExecuteImplied,
+    ReadPc,
+    // BRK skips the byte after the opcode, so RTI returns past it.
+    ReadPadding,
+    ReadStack,
+    PushRegister,
+    PullRegister,
+    PushPcHigh,
+    PushPcLow,
+    PushStatus,
+    FetchVectorLow,
+    FetchVectorHigh,
+    PullStatus,
+    PullPcLow,
+    PullPcHigh,
+    IncrementPc,
+    // Fetches the high byte of the target and jumps there.
+    Jump,
+    FetchIndirectLow,
+    FetchIndirectHigh,
+    FetchBranchOffset,
+    Branch,
+    BranchFixUp,
 }
 
-impl Cpu {
-    #[must_use]
-    #[inline(always)]
-    fn set_memory_value(&mut self, address: u16, value: u8) -> Option<SideEffect> {
-        self.bus.write_address(address, value);
-
-        match address {
-            0x2003 => Some(SideEffect::WriteOamAddr(value)),
-            0x2004 => Some(SideEffect::WriteOamData(value)),
-            0x4014 => Some(SideEffect::OamDma(value)),
-            0x2006 => Some(SideEffect::WritePpuAddr(value)),
-            0x2007 => Some(SideEffect::WritePpuData(value)),
-            0x2000 => Some(SideEffect::SetPpuControl(value)),
-            _ => None,
-        }
-    }
-
-    pub fn enter_nmi(&mut self) {
-        let addresses = self.pc.to_le_bytes();
-        self.push(addresses[1]);
-        self.push(addresses[0]);
-        self.push(self.p.bitand(!(1 << 5)));
-        self.pc = self.nmi_vector;
+use MicroOp::*;
+
+// The cycles working out the effective address of each addressing mode.
+const ZERO_PAGE: &[MicroOp] = &[FetchZeroPage];
+const ZERO_PAGE_X: &[MicroOp] = &[FetchZeroPage, IndexZeroPage(Index::X)];
+const ZERO_PAGE_Y: &[MicroOp] = &[FetchZeroPage, IndexZeroPage(Index::Y)];
+const ABSOLUTE: &[MicroOp] = &[FetchAddressLow, FetchAddressHigh];
+const ABSOLUTE_X: &[MicroOp] = &[FetchAddressLow, FetchAddressHighIndexed(Index::X)];
+const ABSOLUTE_Y: &[MicroOp] = &[FetchAddressLow, FetchAddressHighIndexed(Index::Y)];
+const INDEXED_INDIRECT: &[MicroOp] = &[
+    FetchPointer,
+    IndexPointer,
+    FetchPointerLow,
+    FetchPointerHigh,
+];
+const INDIRECT_INDEXED: &[MicroOp] = &[FetchPointer, FetchPointerLow, FetchPointerHighIndexed];
+
+// The cycles accessing the effective address. Indexed reads only spend a cycle
+// fixing up the high byte of the address when the index crosses a page, writes and
+// read-modify-writes always spend it.
+const READ: &[MicroOp] = &[ReadOperand];
+const READ_INDEXED: &[MicroOp] = &[ReadOrFixUp, ReadOperand];
+const WRITE: &[MicroOp] = &[WriteResult];
+const WRITE_INDEXED: &[MicroOp] = &[FixUp, WriteResult];
+const READ_MODIFY_WRITE: &[MicroOp] = &[ReadForModify, WriteUnmodified, WriteModified];
+const READ_MODIFY_WRITE_INDEXED: &[MicroOp] =
+    &[FixUp, ReadForModify, WriteUnmodified, WriteModified];
+const IMPLIED: &[MicroOp] = &[ExecuteImplied];
+const IMMEDIATE: &[MicroOp] = &[FetchImmediate];
+
+// Instructions with cycles of their own.
+const BRK: &[MicroOp] = &[
+    ReadPadding,
+    PushPcHigh,
+    PushPcLow,
+    PushStatus,
+    FetchVectorLow,
+    FetchVectorHigh,
+];
+const JSR: &[MicroOp] = &[FetchAddressLow, ReadStack, PushPcHigh, PushPcLow, Jump];
+const RTI: &[MicroOp] = &[ReadPc, ReadStack, PullStatus, PullPcLow, PullPcHigh];
+const RTS: &[MicroOp] = &[ReadPc, ReadStack, PullPcLow, PullPcHigh, IncrementPc];
+const PUSH: &[MicroOp] = &[ReadPc, PushRegister];
+const PULL: &[MicroOp] = &[ReadPc, ReadStack, PullRegister];
+const JMP_ABSOLUTE: &[MicroOp] = &[FetchAddressLow, Jump];
+const JMP_INDIRECT: &[MicroOp] = &[
+    FetchAddressLow,
+    FetchAddressHigh,
+    FetchIndirectLow,
+    FetchIndirectHigh,
+];
+// A branch that isn't taken ends after the offset, one that stays on the same page
+// after `Branch`.
+const BRANCH: &[MicroOp] = &[FetchBranchOffset, Branch, BranchFixUp];
+
+// The cycles of `instruction` after its opcode fetch: the ones working out the
+// effective address, then the ones accessing it.
+fn micro_ops(instruction: Instruction) -> [&'static [MicroOp]; 2] {
+    use AddressingMode as Mode;
+
+    let operation = instruction.operation;
+    let whole = match operation {
+        Operation::Brk => Some(BRK),
+        Operation::Jsr => Some(JSR),
+        Operation::Rti => Some(RTI),
+        Operation::Rts => Some(RTS),
+        Operation::Pha | Operation::Php => Some(PUSH),
+        Operation::Pla | Operation::Plp => Some(PULL),
+        Operation::Jmp if instruction.mode == Mode::Absolute => Some(JMP_ABSOLUTE),
+        Operation::Jmp => Some(JMP_INDIRECT),
+        _ => None,
+    };
+    if let Some(micro_ops) = whole {
+        return [micro_ops, &[]];
     }
 
-    pub fn enter_irq(&mut self) {
-        if !self.is_interrupt_disable_flag_on() {
-            self.consume_pending_interrupt_flag();
-
-            let addresses = self.pc.to_le_bytes();
-            self.push(addresses[1]);
-            self.push(addresses[0]);
-            self.push(self.p.bitand(!(1 << 5)));
-            self.pc = self.irq_vector;
-            self.set_interrupt_disable_flag(true);
-        }
-    }
-
-    fn consume_pending_interrupt_flag(&mut self) {
-        if let Some(is_on) = self.delayed_i_flag {
-            self.set_interrupt_disable_flag(is_on as bool);
-            self.delayed_i_flag = None;
+    let address = match instruction.mode {
+        Mode::Implied | Mode::Accumulator => return [&[], IMPLIED],
+        Mode::Immediate => return [&[], IMMEDIATE],
+        Mode::Relative => return [BRANCH, &[]],
+        Mode::Indirect => unreachable!("only JMP is indirect"),
+        Mode::ZeroPage => ZERO_PAGE,
+        Mode::ZeroPageX => ZERO_PAGE_X,
+        Mode::ZeroPageY => ZERO_PAGE_Y,
+        Mode::Absolute => ABSOLUTE,
+        Mode::AbsoluteX => ABSOLUTE_X,
+        Mode::AbsoluteY => ABSOLUTE_Y,
+        Mode::IndexedIndirect => INDEXED_INDIRECT,
+        Mode::IndirectIndexed => INDIRECT_INDEXED,
+    };
+
+    let indexed = matches!(
+        instruction.mode,
+        Mode::AbsoluteX | Mode::AbsoluteY | Mode::IndirectIndexed
+    );
+    let access = match operation {
+        Operation::Sta | Operation::Stx | Operation::Sty | Operation::Sax if indexed => {
+            WRITE_INDEXED
         }
-    }
-
-    pub fn step(&mut self) -> CpuResult {
-        // println!("PC = {:#06x}", self.pc);
-        self.consume_pending_interrupt_flag();
-
-        let instruction = Instruction::from_bytes(self)
-            .map_err(|opcode| {
-                format!(
-                    "Failed parsing opcode: {:#02X?} at pc: {:02X?}",
-                    opcode,
-                    self.pc - 1
-                )
-            })
-            .unwrap();
-
-        // println!("{:#04X?}", instruction);
-
-        match instruction {
-            Instruction::AndImmediate(value) => {
-                self.and(value);
-                cycles(2)
-            }
-
-            Instruction::AndXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.and(value);
-                cycles(6)
-            }
-
-            Instruction::AndYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.and(value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::AndZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.and(value);
-                cycles(3)
-            }
-
-            Instruction::AndXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.and(value);
-                cycles(4)
-            }
-
-            Instruction::AndAbsolute(address) => {
-                let value = self.bus.read_address(address);
-
-                self.and(value);
-                cycles(4)
-            }
-
-            Instruction::AndXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.and(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::AndYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.and(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::OraImmediate(value) => {
-                self.or(value);
-                cycles(2)
-            }
-
-            Instruction::OraXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.or(value);
-                cycles(6)
-            }
-
-            Instruction::OraZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.or(value);
-                cycles(3)
-            }
-
-            Instruction::OraXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.or(value);
-                cycles(4)
-            }
-
-            Instruction::OraAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.or(value);
-                cycles(4)
-            }
-
-            Instruction::OraXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.or(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::OraYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-
-                self.or(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::OraYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.or(value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::EorImmediate(value) => {
-                self.exor(value);
-                cycles(2)
-            }
-
-            Instruction::EorXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.exor(value);
-                cycles(6)
-            }
-
-            Instruction::EorYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.exor(value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::EorZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.exor(value);
-                cycles(3)
-            }
-
-            Instruction::EorXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.exor(value);
-                cycles(4)
-            }
-
-            Instruction::EorAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.exor(value);
-                cycles(4)
-            }
-
-            Instruction::EorXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.exor(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::EorYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.exor(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::AdcImmediate(value) => {
-                self.adc(value);
-                cycles(2)
-            }
-
-            Instruction::AdcXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.adc(value);
-                cycles(6)
-            }
-
-            Instruction::AdcYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.adc(value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::AdcZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.adc(value);
-                cycles(3)
-            }
-
-            Instruction::AdcXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.adc(value);
-                cycles(4)
-            }
-
-            Instruction::AdcAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.adc(value);
-                cycles(4)
-            }
-
-            Instruction::AdcXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.adc(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::AdcYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.adc(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::SbcImmediate(value) | Instruction::SbcImmediateIllegal(value) => {
-                self.sbc(value);
-                cycles(2)
-            }
-
-            Instruction::SbcXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.sbc(value);
-                cycles(6)
-            }
-
-            Instruction::SbcYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.sbc(value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::SbcZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.sbc(value);
-                cycles(3)
-            }
-
-            Instruction::SbcXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.sbc(value);
-                cycles(4)
-            }
-
-            Instruction::SbcAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.sbc(value);
-                cycles(4)
-            }
-
-            Instruction::SbcXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.sbc(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::SbcYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.sbc(value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::CmpImmediate(value) => {
-                self.compare(self.a, value);
-                cycles(2)
-            }
-
-            Instruction::CmpXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.compare(self.a, value);
-                cycles(6)
-            }
-
-            Instruction::CmpYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.compare(self.a, value);
-                cycles(5 + overflow as u32)
-            }
-
-            Instruction::CmpZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.compare(self.a, value);
-                cycles(3)
-            }
-
-            Instruction::CmpXZeroPage(address) => {
-                let value = self.zero_page_value(address, self.x);
-                self.compare(self.a, value);
-                cycles(4)
-            }
-
-            Instruction::CmpAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.compare(self.a, value);
-                cycles(4)
-            }
-
-            Instruction::CmpXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.compare(self.a, value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::CmpYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.compare(self.a, value);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::CpxImmediate(value) => {
-                self.compare(self.x, value);
-                cycles(2)
-            }
-
-            Instruction::CpxZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.compare(self.x, value);
-                cycles(3)
-            }
-
-            Instruction::CpxAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.compare(self.x, value);
-                cycles(4)
-            }
-
-            Instruction::CpyImmediate(value) => {
-                self.compare(self.y, value);
-                cycles(2)
-            }
-
-            Instruction::CpyZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.compare(self.y, value);
-                cycles(3)
-            }
-
-            Instruction::CpyAbsolute(address) => {
-                let value = self.bus.read_address(address);
-                self.compare(self.y, value);
-                cycles(4)
-            }
-
-            Instruction::Lsr => {
-                self.a = self.lsr(self.a);
-
-                cycles(2)
-            }
-
-            Instruction::LsrZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.lsr_address(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::LsrXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.lsr_address(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::LsrAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.lsr_address(address),
-                has_dma: false,
-            },
-
-            Instruction::LsrXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.lsr_address(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::Asl => {
-                self.a = self.asl(self.a);
-                cycles(2)
-            }
-
-            Instruction::AslZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.asl_address(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::AslXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.asl_address(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::AslAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.asl_address(address),
-                has_dma: false,
-            },
-
-            Instruction::AslXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.asl_address(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::Ror => {
-                self.a = self.ror(self.a);
-                cycles(2)
-            }
-
-            Instruction::RorZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.ror_address(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::RorXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.ror_address(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::RorAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.ror_address(address),
-                has_dma: false,
-            },
-
-            Instruction::RorXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.ror_address(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::Rol => {
-                self.a = self.rol(self.a);
-                cycles(2)
-            }
-
-            Instruction::RolZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.rol_address(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::RolXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rol_address(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::RolAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rol_address(address),
-                has_dma: false,
-            },
-
-            Instruction::RolXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.rol_address(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::IncZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.inc(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::IncXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.inc(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::IncAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.inc(address),
-                has_dma: false,
-            },
-
-            Instruction::IncXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.inc(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::DecZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.dec(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::DecXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.dec(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::DecAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.dec(address),
-                has_dma: false,
-            },
-
-            Instruction::DecXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.dec(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::StaAbsolute(address) => {
-                if address == 0x4014 {
-                    self.bus.write_address(address, self.a);
-                    return CpuResult {
-                        cycles_elapsed: 4,
-                        side_effect: None,
-                        has_dma: true,
-                    };
-                } else {
-                    let side_effect = self.set_memory_value(address, self.a);
-                    return CpuResult {
-                        cycles_elapsed: 4,
-                        side_effect,
-                        has_dma: false,
-                    };
-                }
-            }
-
-            Instruction::StaXAbsolute(address) => {
-                let (address, _) = self.absolute_address(address, self.x);
-                let side_effect = self.set_memory_value(address, self.a);
-
-                CpuResult {
-                    cycles_elapsed: 5,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StaYAbsolute(address) => {
-                let (address, _) = self.absolute_address(address, self.y);
-                let side_effect = self.set_memory_value(address, self.a);
-
-                CpuResult {
-                    cycles_elapsed: 5,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::LdxImmediate(value) => {
-                self.x = value;
-                self.toggle_zero_negative_flag(self.x);
-
-                cycles(2)
-            }
-
-            Instruction::LdxZeroPage(address) => {
-                self.x = self.bus.read_address(address as u16);
-                self.toggle_zero_negative_flag(self.x);
-
-                cycles(3)
-            }
-
-            Instruction::LdxYZeroPage(address) => {
-                self.x = self.zero_page_value(address, self.y);
-                self.toggle_zero_negative_flag(self.x);
-
-                cycles(4)
-            }
-
-            Instruction::LdxAbsolute(address) => {
-                self.x = self.bus.read_address(address);
-                self.toggle_zero_negative_flag(self.x);
-
-                cycles(4)
-            }
-
-            Instruction::LdxYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.x = value;
-                self.toggle_zero_negative_flag(self.x);
-
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::LdyImmediate(value) => {
-                self.y = value;
-                self.toggle_zero_negative_flag(self.y);
-
-                cycles(2)
-            }
-
-            Instruction::LdyZeroPage(address) => {
-                self.y = self.bus.read_address(address as u16);
-                self.toggle_zero_negative_flag(self.y);
-
-                cycles(3)
-            }
-
-            Instruction::LdyXZeroPage(address) => {
-                self.y = self.zero_page_value(address, self.x);
-                self.toggle_zero_negative_flag(self.y);
-
-                cycles(4)
-            }
-
-            Instruction::LdyAbsolute(address) => {
-                self.y = self.bus.read_address(address);
-                self.toggle_zero_negative_flag(self.y);
-
-                cycles(4)
-            }
-
-            Instruction::LdyXAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.x);
-                self.y = value;
-                self.toggle_zero_negative_flag(self.y);
-
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::LdaImmediate(value) => {
-                self.a = value;
-
-                self.toggle_zero_negative_flag(self.a);
-                cycles(2)
-            }
-
-            Instruction::LdaAbsolute(address) => {
-                self.a = self.bus.read_address(address);
-                self.toggle_zero_negative_flag(self.a);
-
-                cycles(4)
-            }
-
-            Instruction::LdaZeroPage(address) => {
-                self.a = self.bus.read_address(address as u16);
-                self.toggle_zero_negative_flag(self.a);
-
-                cycles(3)
-            }
-
-            Instruction::LdaXZeroPage(address) => {
-                self.a = self.zero_page_value(address, self.x);
-                self.toggle_zero_negative_flag(self.a);
-
-                cycles(4)
-            }
-
-            Instruction::LdaXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-
-                self.a = self.bus.read_address(address);
-
-                self.toggle_zero_negative_flag(self.a);
-                cycles(6)
-            }
-
-            Instruction::LdaYIndirectIndexed(index) => {
-                let (value, overflow) = self.indirect_indexed_value(index);
-                self.a = value;
-                self.toggle_zero_negative_flag(self.a);
-                cycles(5 + (overflow) as u32)
-            }
-
-            Instruction::LdaXAbsolute(address) => {
-                let (address, carry) = self.absolute_address(address, self.x);
-
-                self.a = self.bus.read_address(address);
-
-                self.toggle_zero_negative_flag(self.a);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::LdaYAbsolute(address) => {
-                let (address, carry) = self.absolute_address(address, self.y);
-
-                self.a = self.bus.read_address(address);
-
-                self.toggle_zero_negative_flag(self.a);
-                cycles(4 + carry as u32)
-            }
-
-            Instruction::Beq(offset) => self.jump_if(self.is_zero_flag_on(), offset),
-
-            Instruction::Inx => {
-                self.x = self.x.overflowing_add(1).0;
-                self.toggle_zero_negative_flag(self.x);
-                cycles(2)
-            }
-
-            Instruction::Iny => {
-                self.y = self.y.overflowing_add(1).0;
-                self.toggle_zero_negative_flag(self.y);
-                cycles(2)
-            }
-
-            Instruction::Dex => {
-                self.x = self.x.overflowing_sub(1).0;
-                self.toggle_zero_negative_flag(self.x);
-                cycles(2)
-            }
-
-            Instruction::Dey => {
-                self.y = self.y.overflowing_sub(1).0;
-                self.toggle_zero_negative_flag(self.y);
-                cycles(2)
-            }
-
-            Instruction::Tay => {
-                self.y = self.a;
-                self.toggle_zero_negative_flag(self.y);
-                cycles(2)
-            }
-
-            Instruction::Tya => {
-                self.a = self.y;
-                self.toggle_zero_negative_flag(self.a);
-                cycles(2)
-            }
-
-            Instruction::Tax => {
-                self.x = self.a;
-                self.toggle_zero_negative_flag(self.x);
-                cycles(2)
-            }
-
-            Instruction::Txa => {
-                self.a = self.x;
-                self.toggle_zero_negative_flag(self.a);
-                cycles(2)
-            }
-
-            Instruction::Tsx => {
-                self.x = self.sp;
-                self.toggle_zero_negative_flag(self.x);
-                cycles(2)
-            }
-
-            Instruction::Txs => {
-                self.sp = self.x;
-                cycles(2)
-            }
-
-            Instruction::JmpAbsolute(address) => {
-                self.pc = address;
-                cycles(3)
-            }
-
-            Instruction::JmpIndirect(address) => {
-                let low_byte = self.bus.read_address(address);
-
-                /*
-                    http://nesdev.com/6502_cpu.txt
-
-                    Indirect addressing modes do not handle page boundary crossing at all.
-                    When the parameter's low byte is $FF, the effective address wraps
-                    around and the CPU fetches high byte from $xx00 instead of $xx00+$0100.
-                    E.g. JMP ($01FF) fetches PCL from $01FF and PCH from $0100,
-                    and LDA ($FF),Y fetches the base address from $FF and $00.
-                */
-                let mut high_address_split = address.to_le_bytes();
-                high_address_split[0] = high_address_split[0].overflowing_add(1).0;
-
-                let high_address = u16::from_le_bytes(high_address_split);
-                let high_byte = self.bus.read_address(high_address as u16);
-
-                self.pc = u16::from_le_bytes([low_byte, high_byte]);
-
-                cycles(5)
-            }
-
-            Instruction::Bne(offset) => self.jump_if(!self.is_zero_flag_on(), offset),
-
-            Instruction::Brk => {
-                self.set_break_flag(true);
-                // self.pc = self.irq_vector;
-                cycles(7)
-            }
-
-            Instruction::StaZeroPage(address) => {
-                let side_effect = self.set_memory_value(address as u16, self.a);
-                CpuResult {
-                    cycles_elapsed: 3,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StaXZeroPage(address) => {
-                let side_effect =
-                    self.set_memory_value(self.zero_page_address(address, self.x) as u16, self.a);
-
-                CpuResult {
-                    cycles_elapsed: 4,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StxZeroPage(address) => {
-                let side_effect = self.set_memory_value(address as u16, self.x);
-                CpuResult {
-                    cycles_elapsed: 3,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StxYZeroPage(address) => {
-                let side_effect =
-                    self.set_memory_value(self.zero_page_address(address, self.y) as u16, self.x);
-
-                CpuResult {
-                    cycles_elapsed: 4,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StxAbsolute(address) => {
-                let side_effect = self.set_memory_value(address, self.x);
-                CpuResult {
-                    cycles_elapsed: 4,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StaXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                let side_effect = self.set_memory_value(address, self.a);
-
-                CpuResult {
-                    cycles_elapsed: 6,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StaYIndirectIndexed(index) => {
-                let (address, _) = self.indirect_indexed_address(index);
-                let side_effect = self.set_memory_value(address, self.a);
-
-                CpuResult {
-                    cycles_elapsed: 6,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StyZeroPage(address) => {
-                let side_effect = self.set_memory_value(address as u16, self.y);
-
-                CpuResult {
-                    cycles_elapsed: 3,
-                    side_effect,
-                    has_dma: false,
-                }
+        Operation::Sta | Operation::Stx | Operation::Sty | Operation::Sax => WRITE,
+        Operation::Asl
+        | Operation::Lsr
+        | Operation::Rol
+        | Operation::Ror
+        | Operation::Inc
+        | Operation::Dec
+        | Operation::Slo
+        | Operation::Sre
+        | Operation::Rla
+        | Operation::Rra
+        | Operation::Isb
+        | Operation::Dcp => {
+            if indexed {
+                READ_MODIFY_WRITE_INDEXED
+            } else {
+                READ_MODIFY_WRITE
             }
+        }
+        _ if indexed => READ_INDEXED,
+        _ => READ,
+    };
 
-            Instruction::StyXZeroPage(address) => {
-                let side_effect =
-                    self.set_memory_value(self.zero_page_address(address, self.x) as u16, self.y);
-
-                CpuResult {
-                    cycles_elapsed: 4,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::StyAbsolute(address) => {
-                let side_effect = self.set_memory_value(address, self.y);
-
-                CpuResult {
-                    cycles_elapsed: 4,
-                    side_effect,
-                    has_dma: false,
-                }
-            }
-
-            Instruction::JsrAbsolute(address) => {
-                let bytes = u16::to_le_bytes(self.pc - 1);
-                self.push(bytes[1]);
-                self.push(bytes[0]);
-
-                self.pc = address;
-                cycles(6)
-            }
-
-            Instruction::Rts => {
-                let low_byte = self.pop();
-                let high_byte = self.pop();
-
-                let address = u16::from_le_bytes([low_byte, high_byte]);
-
-                self.pc = address + 1;
-                cycles(6)
-            }
-
-            Instruction::Rti => {
-                self.p = self.pop().bitor(1 << 5); // bit 5 is always on
-                let low_byte = self.pop();
-                let high_byte = self.pop();
-
-                let address = u16::from_le_bytes([low_byte, high_byte]);
-
-                self.pc = address;
-                cycles(6)
-            }
-
-            Instruction::Nop => cycles(2),
-
-            Instruction::Sec => {
-                self.set_carry_flag(true);
-                cycles(2)
-            }
-
-            Instruction::Clc => {
-                self.set_carry_flag(false);
-                cycles(2)
-            }
-
-            Instruction::Clv => {
-                self.set_overflow_flag(false);
-                cycles(2)
-            }
-
-            Instruction::Bcs(offset) => self.jump_if(self.is_carry_flag_on(), offset),
-
-            Instruction::Bcc(offset) => self.jump_if(!self.is_carry_flag_on(), offset),
-
-            Instruction::BitZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-
-                self.set_negative_flag(value & 0x80 != 0);
-                self.set_overflow_flag(value & 0x40 != 0);
-                self.set_zero_flag((value & self.a) == 0);
-                cycles(3)
-            }
-
-            Instruction::BitAbsolute(address) => {
-                let value = self.bus.read_address(address);
-
-                self.set_negative_flag(value & 0x80 != 0);
-                self.set_overflow_flag(value & 0x40 != 0);
-                self.set_zero_flag((value & self.a) == 0);
-                cycles(4)
-            }
+    [address, access]
+}
 
-            Instruction::Bvs(offset) => self.jump_if(self.is_overflow_flag_on(), offset),
+/// Runs one bus access per cycle, during which the rest of the machine runs too,
+/// so the PPU and APU see reads and writes at the exact cycle they happen, dummy
+/// accesses included. Each instruction is a sequence of micro-ops, one per cycle
+/// after the opcode fetch.
+pub struct Cpu {
+    // memory: MemoryBuffer,
+    pc: u16,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    sp: u8,
 
-            Instruction::Bvc(offset) => self.jump_if(!self.is_overflow_flag_on(), offset),
+    pub bus: RealBus,
 
-            Instruction::Bpl(offset) => self.jump_if(!self.is_negative_flag_on(), offset),
+    // NMI is edge triggered and IRQ level triggered. Both are sampled at the end of
+    // every cycle, and the CPU acts on what it saw at the end of an instruction's
+    // second to last cycle.
+    nmi_line: bool,
+    nmi_pending: bool,
+    previous_nmi_pending: bool,
+    irq_pending: bool,
+    previous_irq_pending: bool,
+
+    // The page a $4014 write asked to copy to OAM, until the next read starts the copy.
+    oam_dma_page: Option<u8>,
+
+    // The instruction being run, `None` between instructions, its micro-ops and
+    // how many of them ran. An interrupt runs as a BRK that leaves PC alone and
+    // pushes the flags with B clear.
+    instruction: Option<Instruction>,
+    opcode: u8,
+    micro_ops: [&'static [MicroOp]; 2],
+    next_micro_op: usize,
+    servicing_interrupt: bool,
+
+    // What the micro-ops work out for the ones that follow.
+    address: u16,
+    pointer: u8,
+    value: u8,
+    page_crossed: bool,
+}
 
-            Instruction::Bmi(offset) => self.jump_if(self.is_negative_flag_on(), offset),
+impl Cpu {
+    #[inline(always)]
+    fn read(&mut self, address: u16) -> u8 {
+        if self.oam_dma_page.is_some() || self.bus.apu.dmc_dma_address().is_some() {
+            self.run_pending_dma(address);
+        }
 
-            Instruction::Sei => {
-                self.delayed_i_flag = Some(true);
-                cycles(2)
-            }
+        self.bus.tick();
+        let value = self.bus.read_address(address);
+        self.poll_interrupts();
 
-            Instruction::Cli => {
-                self.delayed_i_flag = Some(false);
-                cycles(2)
-            }
+        value
+    }
 
-            Instruction::Cld => {
-                self.set_decimal_flag(false);
-                cycles(2)
-            }
+    #[inline(always)]
+    fn write(&mut self, address: u16, value: u8) {
+        self.bus.tick();
+        if self.bus.write_address(address, value) {
+            self.oam_dma_page = Some(value);
+        }
+        self.poll_interrupts();
+    }
 
-            Instruction::Sed => {
-                self.set_decimal_flag(true);
-                cycles(2)
+    // The DMA units can only halt the CPU on a read. The CPU repeats the read
+    // while halted, then the DMAs take over the bus: they read on even cycles and
+    // OAM DMA writes on odd ones, with the DMC going first. Cycles neither of them
+    // can use are spent repeating the read again. Depending on the alignment and
+    // on an OAM DMA running, a DMC fetch costs the CPU 1 to 4 cycles.
+    fn run_pending_dma(&mut self, address: u16) {
+        // Repeating these reads would clock the joypads.
+        let skips_dummy_reads = address == 0x4016 || address == 0x4017;
+
+        let mut dmc_running = false;
+        let mut dmc_dummy_read_needed = false;
+        self.begin_dma_cycle(&mut dmc_running, &mut dmc_dummy_read_needed);
+        if !skips_dummy_reads {
+            self.bus.read_address(address);
+        }
+        self.poll_interrupts();
+
+        let oam_page = self.oam_dma_page.take();
+        let mut oam_accesses = 0;
+        let mut oam_value = 0;
+
+        while dmc_running || (oam_page.is_some() && oam_accesses < 512) {
+            let get_cycle = (self.bus.cycle() + 1) & 1 == 0;
+            let dmc_ready = dmc_running && !dmc_dummy_read_needed;
+
+            dmc_dummy_read_needed = false;
+            self.begin_dma_cycle(&mut dmc_running, &mut dmc_dummy_read_needed);
+
+            match oam_page {
+                _ if get_cycle && dmc_ready => {
+                    // Turning the channel off can cancel the fetch before it happens.
+                    if let Some(dmc_address) = self.bus.apu.dmc_dma_address() {
+                        let value = self.bus.read_address(dmc_address);
+                        self.bus.apu.finish_dmc_dma(value);
+                    }
+                    dmc_running = false;
+                }
+                Some(page) if get_cycle && oam_accesses < 512 => {
+                    oam_value = self
+                        .bus
+                        .read_address(u16::from_le_bytes([(oam_accesses / 2) as u8, page]));
+                    oam_accesses += 1;
+                }
+                Some(_) if !get_cycle && oam_accesses & 1 == 1 => {
+                    self.bus.write_address(0x2004, oam_value);
+                    oam_accesses += 1;
+                }
+                _ => {
+                    if !skips_dummy_reads {
+                        self.bus.read_address(address);
+                    }
+                }
             }
 
-            Instruction::Php => {
-                self.push(self.p.bitor(0x10));
-
-                cycles(3)
-            }
+            self.poll_interrupts();
+        }
+    }
 
-            Instruction::Pha => {
-                self.push(self.a);
+    // The DMC asks for a byte as soon as its buffer empties, and needs a cycle to
+    // halt the CPU and one more to line up before it can fetch it.
+    #[inline(always)]
+    fn begin_dma_cycle(&mut self, dmc_running: &mut bool, dmc_dummy_read_needed: &mut bool) {
+        if !*dmc_running && self.bus.apu.dmc_dma_address().is_some() {
+            *dmc_running = true;
+            *dmc_dummy_read_needed = true;
+        }
 
-                cycles(3)
-            }
+        self.bus.tick();
+    }
 
-            Instruction::Pla => {
-                self.a = self.pop();
+    fn poll_interrupts(&mut self) {
+        let nmi_line = self.bus.ppu.nmi_line();
+        self.previous_nmi_pending = self.nmi_pending;
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
 
-                self.toggle_zero_negative_flag(self.a);
+        self.previous_irq_pending = self.irq_pending;
+        self.irq_pending = self.bus.has_pending_irq() && !self.is_interrupt_disable_flag_on();
+    }
 
-                cycles(4)
-            }
+    /// Runs one instruction, or the interrupt sequence if an interrupt is due,
+    /// one cycle at a time, and returns how many cycles it took.
+    pub fn step(&mut self) -> u32 {
+        let start_cycle = self.bus.cycle();
 
-            Instruction::Plp => {
-                let value = self.pop().bitand(!(1 << 4)).bitor(1 << 5); // this bit is always on
+        self.run_cycle();
+        while self.instruction.is_some() {
+            self.run_cycle();
+        }
 
-                let current_i_flag = self.is_interrupt_disable_flag_on();
-                self.p = value;
+        (self.bus.cycle() - start_cycle) as u32
+    }
 
-                self.delayed_i_flag = Some(value & 4 != 0);
+    // Runs one cycle: the opcode fetch of the next instruction, or the next
+    // micro-op of the current one.
+    fn run_cycle(&mut self) {
+        let instruction = match self.instruction {
+            Some(instruction) => instruction,
+            None => return self.fetch_opcode(),
+        };
 
-                self.set_interrupt_disable_flag(current_i_flag);
+        let [address_micro_ops, access_micro_ops] = self.micro_ops;
+        let index = self.next_micro_op;
+        let micro_op = match address_micro_ops.get(index) {
+            Some(micro_op) => *micro_op,
+            None => access_micro_ops[index - address_micro_ops.len()],
+        };
+        self.next_micro_op += 1;
 
-                cycles(4)
-            }
+        let finished = self.run_micro_op(micro_op, instruction.operation);
+        if finished || self.next_micro_op == address_micro_ops.len() + access_micro_ops.len() {
+            self.instruction = None;
+        }
+    }
 
-            // illegal instructions
-            Instruction::Nop2 | Instruction::NopImmediate(_) => cycles(2),
-            Instruction::NopZeroPage(_) => cycles(3),
-            Instruction::NopXZeroPage(_) => cycles(4),
-            Instruction::NopAbsolute(_) => cycles(4),
-            Instruction::NopXAbsolute(address) => {
-                let (_, carry) = self.absolute_address(address, self.x);
-                cycles(4 + carry as u32)
-            }
+    // An interrupt fetches the opcode too, but throws it away and runs BRK instead.
+    fn fetch_opcode(&mut self) {
+        self.servicing_interrupt = self.previous_nmi_pending || self.previous_irq_pending;
+        self.opcode = if self.servicing_interrupt {
+            self.read(self.pc);
+            0x00
+        } else {
+            self.fetch()
+        };
 
-            Instruction::LaxXIndexedIndirect(index) => {
-                let value = self.indexed_indirect_value(index);
-                self.lax(value);
-                cycles(6)
-            }
+        let instruction = Instruction::decode(self.opcode).unwrap_or_else(|| {
+            panic!(
+                "Failed parsing opcode: {:#04X} at pc: {:#06X}",
+                self.opcode,
+                self.pc.wrapping_sub(1)
+            )
+        });
+        self.instruction = Some(instruction);
+        self.micro_ops = micro_ops(instruction);
+        self.next_micro_op = 0;
+    }
 
-            Instruction::LaxYIndirectIndexed(index) => {
-                let (value, carry) = self.indirect_indexed_value(index);
-                self.lax(value);
-                cycles(5 + carry as u32)
-            }
+    // Returns true if the instruction ends early, after this cycle.
+    #[inline(always)]
+    fn run_micro_op(&mut self, micro_op: MicroOp, operation: Operation) -> bool {
+        match micro_op {
+            FetchImmediate => {
+                let value = self.fetch();
+                self.execute_read(operation, value);
+            }
+            FetchZeroPage | FetchAddressLow => self.address = self.fetch() as u16,
+            FetchAddressHigh => {
+                let high = self.fetch();
+                self.address |= (high as u16) << 8;
+            }
+            FetchAddressHighIndexed(index) => {
+                let high = self.fetch();
+                self.index_address(high, index);
+            }
+            IndexZeroPage(index) => {
+                self.read(self.address);
+                self.address = (self.address as u8).wrapping_add(self.index(index)) as u16;
+            }
+            FetchPointer => self.pointer = self.fetch(),
+            IndexPointer => {
+                self.read(self.pointer as u16);
+                self.pointer = self.pointer.wrapping_add(self.x);
+            }
+            FetchPointerLow => self.address = self.read(self.pointer as u16) as u16,
+            FetchPointerHigh => {
+                let high = self.read(self.pointer.wrapping_add(1) as u16);
+                self.address |= (high as u16) << 8;
+            }
+            FetchPointerHighIndexed => {
+                let high = self.read(self.pointer.wrapping_add(1) as u16);
+                self.index_address(high, Index::Y);
+            }
+            ReadOrFixUp => {
+                let value = self.read(self.address);
+                if !self.page_crossed {
+                    self.execute_read(operation, value);
+                    return true;
+                }
 
-            Instruction::LaxZeroPage(address) => {
-                let value = self.bus.read_address(address as u16);
-                self.lax(value);
-                cycles(3)
+                self.address = self.address.wrapping_add(0x100);
             }
-
-            Instruction::LaxYZeroPage(address) => {
-                let value = self.zero_page_value(address, self.y);
-                self.lax(value);
-                cycles(4)
+            FixUp => {
+                self.read(self.address);
+                if self.page_crossed {
+                    self.address = self.address.wrapping_add(0x100);
+                }
             }
-
-            Instruction::LaxAbsolute(address) => {
-                let value = self.absolute_value(address, 0).0;
-                self.lax(value);
-                cycles(4)
+            ReadOperand => {
+                let value = self.read(self.address);
+                self.execute_read(operation, value);
             }
-
-            Instruction::LaxYAbsolute(address) => {
-                let (value, carry) = self.absolute_value(address, self.y);
-                self.lax(value);
-                cycles(4 + carry as u32)
+            WriteResult => {
+                let value = self.result(operation);
+                self.write(self.address, value);
             }
-
-            Instruction::SaxXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-
-                CpuResult {
-                    cycles_elapsed: 6,
-                    side_effect: self.sax(address),
-                    has_dma: false,
-                }
+            ReadForModify => self.value = self.read(self.address),
+            WriteUnmodified => {
+                self.write(self.address, self.value);
+                self.value = self.modify(operation, self.value);
             }
-
-            Instruction::SaxZeroPage(address) => CpuResult {
-                cycles_elapsed: 3,
-                side_effect: self.sax(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::SaxYZeroPage(address) => CpuResult {
-                cycles_elapsed: 4,
-                side_effect: self.sax(self.zero_page_address(address, self.y) as u16),
-                has_dma: false,
-            },
-
-            Instruction::SaxAbsolute(address) => CpuResult {
-                cycles_elapsed: 4,
-                side_effect: self.sax(address),
-                has_dma: false,
-            },
-
-            Instruction::DcpXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.dcp(address),
-                    has_dma: false,
-                }
+            WriteModified => self.write(self.address, self.value),
+            ExecuteImplied => {
+                self.read(self.pc);
+                self.execute_implied(operation);
             }
-
-            Instruction::DcpYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.dcp(address),
-                    has_dma: false,
-                }
+            ReadPc => {
+                self.read(self.pc);
             }
-
-            Instruction::DcpZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.dcp(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::DcpXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.dcp(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::DcpAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.dcp(address),
-                has_dma: false,
-            },
-
-            Instruction::DcpXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.dcp(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::DcpYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.dcp(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
-
-            Instruction::IsbXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.isb(address),
-                    has_dma: false,
+            ReadPadding => {
+                self.read(self.pc);
+                if !self.servicing_interrupt {
+                    self.pc = self.pc.wrapping_add(1);
                 }
             }
-
-            Instruction::IsbYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.isb(address),
-                    has_dma: false,
-                }
+            ReadStack => {
+                self.read(self.sp as u16 + 0x0100);
             }
-
-            Instruction::IsbZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.isb(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::IsbXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.isb(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::IsbAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.isb(address),
-                has_dma: false,
-            },
-
-            Instruction::IsbXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.isb(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::IsbYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.isb(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
-
-            Instruction::SloXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.slo(address),
-                    has_dma: false,
+            PushRegister => {
+                let value = if operation == Operation::Pha {
+                    self.a
+                } else {
+                    self.p.bitor(0x10)
+                };
+                self.push(value);
+            }
+            PullRegister => {
+                let value = self.pop();
+                if operation == Operation::Pla {
+                    self.a = value;
+                    self.toggle_zero_negative_flag(self.a);
+                } else {
+                    // Like SEI and CLI, a change to the I flag only takes effect after
+                    // the next instruction.
+                    self.p = value.bitand(!(1 << 4)).bitor(1 << 5); // this bit is always on
                 }
             }
+            PushPcHigh => self.push((self.pc >> 8) as u8),
+            PushPcLow => self.push(self.pc as u8),
+            PushStatus => {
+                // An NMI that is pending by the time the flags are pushed takes over
+                // the vector, even if this started as an IRQ or BRK.
+                self.address = if self.nmi_pending {
+                    self.nmi_pending = false;
+                    NMI_VECTOR
+                } else {
+                    IRQ_VECTOR
+                };
 
-            Instruction::SloYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.slo(address),
-                    has_dma: false,
-                }
-            }
+                let flags = if self.servicing_interrupt {
+                    self.p.bitor(1 << 5).bitand(!(1 << 4))
+                } else {
+                    self.p.bitor(0x30)
+                };
+                self.push(flags);
+                self.set_interrupt_disable_flag(true);
+            }
+            FetchVectorLow => self.value = self.read(self.address),
+            FetchVectorHigh => {
+                let high = self.read(self.address + 1);
+                self.pc = u16::from_le_bytes([self.value, high]);
+            }
+            PullStatus => self.p = self.pop().bitand(!(1 << 4)).bitor(1 << 5), // bit 5 is always on
+            PullPcLow => self.value = self.pop(),
+            PullPcHigh => {
+                let high = self.pop();
+                self.pc = u16::from_le_bytes([self.value, high]);
+            }
+            IncrementPc => {
+                self.read(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+            }
+            Jump => {
+                let high = self.read(self.pc);
+                self.pc = u16::from_le_bytes([self.address as u8, high]);
+            }
+            FetchIndirectLow => self.value = self.read(self.address),
+            FetchIndirectHigh => {
+                /*
+                    http://nesdev.com/6502_cpu.txt
 
-            Instruction::SloZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.slo(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::SloXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.slo(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::SloAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.slo(address),
-                has_dma: false,
-            },
-
-            Instruction::SloXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.slo(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::SloYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.slo(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
-
-            Instruction::RlaXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.rla(address),
-                    has_dma: false,
+                    Indirect addressing modes do not handle page boundary crossing at all.
+                    When the parameter's low byte is $FF, the effective address wraps
+                    around and the CPU fetches high byte from $xx00 instead of $xx00+$0100.
+                    E.g. JMP ($01FF) fetches PCL from $01FF and PCH from $0100,
+                    and LDA ($FF),Y fetches the base address from $FF and $00.
+                */
+                let [low, page] = self.address.to_le_bytes();
+                let high = self.read(u16::from_le_bytes([low.wrapping_add(1), page]));
+                self.pc = u16::from_le_bytes([self.value, high]);
+            }
+            FetchBranchOffset => {
+                self.value = self.fetch();
+                if !self.branch_condition(operation) {
+                    return true;
                 }
             }
-
-            Instruction::RlaYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.rla(address),
-                    has_dma: false,
+            // A taken branch reads the next opcode while it adds the offset, and again
+            // while it fixes up the high byte if the target is on another page. An IRQ
+            // that shows up during the offset fetch isn't seen until after the next instruction.
+            Branch => {
+                if self.irq_pending && !self.previous_irq_pending {
+                    self.irq_pending = false;
                 }
-            }
 
-            Instruction::RlaZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.rla(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::RlaXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rla(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::RlaAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rla(address),
-                has_dma: false,
-            },
-
-            Instruction::RlaXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.rla(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::RlaYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.rla(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
-
-            Instruction::SreXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index) as u16;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.sre(address),
-                    has_dma: false,
-                }
-            }
+                self.read(self.pc);
 
-            Instruction::SreYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.sre(address),
-                    has_dma: false,
+                let target = self.pc.wrapping_add(self.value as i8 as u16);
+                if target & 0xff00 == self.pc & 0xff00 {
+                    self.pc = target;
+                    return true;
                 }
-            }
 
-            Instruction::SreZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.sre(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::SreXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.sre(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::SreAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.sre(address),
-                has_dma: false,
-            },
-
-            Instruction::SreXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.sre(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::SreYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.sre(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
-
-            Instruction::RraXIndexedIndirect(index) => {
-                let address = self.indexed_indirect_address(index);
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.rra(address),
-                    has_dma: false,
-                }
+                self.address = target;
+                self.pc = (self.pc & 0xff00) | (target & 0x00ff);
             }
-
-            Instruction::RraYIndirectIndexed(index) => {
-                let address = self.indirect_indexed_address(index).0;
-                CpuResult {
-                    cycles_elapsed: 8,
-                    side_effect: self.rra(address),
-                    has_dma: false,
-                }
+            BranchFixUp => {
+                self.read(self.pc);
+                self.pc = self.address;
             }
-
-            Instruction::RraZeroPage(address) => CpuResult {
-                cycles_elapsed: 5,
-                side_effect: self.rra(address as u16),
-                has_dma: false,
-            },
-
-            Instruction::RraXZeroPage(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rra(self.zero_page_address(address, self.x) as u16),
-                has_dma: false,
-            },
-
-            Instruction::RraAbsolute(address) => CpuResult {
-                cycles_elapsed: 6,
-                side_effect: self.rra(address),
-                has_dma: false,
-            },
-
-            Instruction::RraXAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.rra(self.absolute_address(address, self.x).0),
-                has_dma: false,
-            },
-
-            Instruction::RraYAbsolute(address) => CpuResult {
-                cycles_elapsed: 7,
-                side_effect: self.rra(self.absolute_address(address, self.y).0),
-                has_dma: false,
-            },
         }
-    }
-
-    #[must_use]
-    #[inline(always)]
-    fn dec(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address).overflowing_sub(1).0;
-        let side_effect = self.set_memory_value(address, value);
-        self.toggle_zero_negative_flag(value);
 
-        side_effect
+        false
     }
 
     #[inline(always)]
-    #[must_use]
-    fn inc(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address).overflowing_add(1).0;
-        let side_effect = self.set_memory_value(address, value);
-        self.toggle_zero_negative_flag(value);
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
 
-        side_effect
+        value
     }
 
     #[inline(always)]
-    fn rra(&mut self, address: u16) -> Option<SideEffect> {
-        let side_effect = self.ror_address(address);
-        let value = self.bus.read_address(address);
-        self.adc(value);
-
-        side_effect
+    fn index(&self, index: Index) -> u8 {
+        match index {
+            Index::X => self.x,
+            Index::Y => self.y,
+        }
     }
 
+    // The index is added to the low byte first, the high byte is fixed up a cycle later.
     #[inline(always)]
-    fn sre(&mut self, address: u16) -> Option<SideEffect> {
-        let mut value = self.bus.read_address(address);
-        let carry = value.bitand(1) != 0;
-
-        value >>= 1;
-
-        let side_effect = self.set_memory_value(address, value);
-        self.a ^= value;
-
-        self.toggle_zero_negative_flag(self.a);
-        self.set_carry_flag(carry);
-
-        side_effect
+    fn index_address(&mut self, high: u8, index: Index) {
+        let (low, carry) = (self.address as u8).overflowing_add(self.index(index));
+        self.page_crossed = carry;
+        self.address = u16::from_le_bytes([low, high]);
     }
 
     #[inline(always)]
-    fn rla(&mut self, address: u16) -> Option<SideEffect> {
-        let side_effect = self.rol_address(address);
-        self.a &= self.bus.read_address(address);
-        self.toggle_zero_negative_flag(self.a);
-
-        side_effect
+    fn execute_read(&mut self, operation: Operation, value: u8) {
+        match operation {
+            Operation::Adc => self.adc(value),
+            Operation::And => self.and(value),
+            Operation::Bit => self.bit(value),
+            Operation::Cmp => self.compare(self.a, value),
+            Operation::Cpx => self.compare(self.x, value),
+            Operation::Cpy => self.compare(self.y, value),
+            Operation::Eor => self.exor(value),
+            Operation::Lda => {
+                self.a = value;
+                self.toggle_zero_negative_flag(self.a);
+            }
+            Operation::Ldx => {
+                self.x = value;
+                self.toggle_zero_negative_flag(self.x);
+            }
+            Operation::Ldy => {
+                self.y = value;
+                self.toggle_zero_negative_flag(self.y);
+            }
+            Operation::Ora => self.or(value),
+            Operation::Sbc => self.sbc(value),
+            Operation::Lax => self.lax(value),
+            Operation::Nop => {}
+            _ => unreachable!("{:?} doesn't read memory", operation),
+        }
     }
 
     #[inline(always)]
-    fn slo(&mut self, address: u16) -> Option<SideEffect> {
-        let carry = self.bus.read_address(address).bitand(0x80) != 0;
-        let (value, _) = self.bus.read_address(address).overflowing_shl(1);
-        let side_effect = self.set_memory_value(address, value);
-
-        self.a |= value;
-        self.toggle_zero_negative_flag(self.a);
-        self.set_carry_flag(carry);
-
-        side_effect
+    fn result(&self, operation: Operation) -> u8 {
+        match operation {
+            Operation::Sta => self.a,
+            Operation::Stx => self.x,
+            Operation::Sty => self.y,
+            Operation::Sax => self.a & self.x,
+            _ => unreachable!("{:?} doesn't write memory", operation),
+        }
     }
 
+    // Returns the value written back. The illegal opcodes then combine it with A.
     #[inline(always)]
-    fn isb(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address).overflowing_add(1).0;
-        let side_effect = self.set_memory_value(address, value);
-
-        self.sbc(value);
-        side_effect
+    fn modify(&mut self, operation: Operation, value: u8) -> u8 {
+        match operation {
+            Operation::Asl => self.asl(value),
+            Operation::Lsr => self.lsr(value),
+            Operation::Rol => self.rol(value),
+            Operation::Ror => self.ror(value),
+            Operation::Inc => self.increment(value),
+            Operation::Dec => self.decrement(value),
+            Operation::Slo => {
+                let value = self.asl(value);
+                self.or(value);
+                value
+            }
+            Operation::Sre => {
+                let value = self.lsr(value);
+                self.exor(value);
+                value
+            }
+            Operation::Rla => {
+                let value = self.rol(value);
+                self.and(value);
+                value
+            }
+            Operation::Rra => {
+                let value = self.ror(value);
+                self.adc(value);
+                value
+            }
+            Operation::Isb => {
+                let value = self.increment(value);
+                self.sbc(value);
+                value
+            }
+            Operation::Dcp => {
+                let value = self.decrement(value);
+                self.compare(self.a, value);
+                value
+            }
+            _ => unreachable!("{:?} doesn't modify memory", operation),
+        }
     }
 
     #[inline(always)]
-    fn dcp(&mut self, address: u16) -> Option<SideEffect> {
-        let (value, _) = self.bus.read_address(address).overflowing_sub(1);
-        let side_effect = self.set_memory_value(address, value);
-
-        let (result, overflow) = self.a.overflowing_sub(value);
-        self.toggle_zero_negative_flag(result);
-        self.set_carry_flag(!overflow);
-
-        side_effect
+    fn execute_implied(&mut self, operation: Operation) {
+        match operation {
+            Operation::Asl | Operation::Lsr | Operation::Rol | Operation::Ror => {
+                self.a = self.modify(operation, self.a)
+            }
+            Operation::Inx => {
+                self.x = self.x.wrapping_add(1);
+                self.toggle_zero_negative_flag(self.x);
+            }
+            Operation::Iny => {
+                self.y = self.y.wrapping_add(1);
+                self.toggle_zero_negative_flag(self.y);
+            }
+            Operation::Dex => {
+                self.x = self.x.wrapping_sub(1);
+                self.toggle_zero_negative_flag(self.x);
+            }
+            Operation::Dey => {
+                self.y = self.y.wrapping_sub(1);
+                self.toggle_zero_negative_flag(self.y);
+            }
+            Operation::Tax => {
+                self.x = self.a;
+                self.toggle_zero_negative_flag(self.x);
+            }
+            Operation::Tay => {
+                self.y = self.a;
+                self.toggle_zero_negative_flag(self.y);
+            }
+            Operation::Tsx => {
+                self.x = self.sp;
+                self.toggle_zero_negative_flag(self.x);
+            }
+            Operation::Txa => {
+                self.a = self.x;
+                self.toggle_zero_negative_flag(self.a);
+            }
+            Operation::Txs => self.sp = self.x,
+            Operation::Tya => {
+                self.a = self.y;
+                self.toggle_zero_negative_flag(self.a);
+            }
+            Operation::Clc => self.set_carry_flag(false),
+            Operation::Sec => self.set_carry_flag(true),
+            Operation::Cld => self.set_decimal_flag(false),
+            Operation::Sed => self.set_decimal_flag(true),
+            Operation::Clv => self.set_overflow_flag(false),
+            // The flag changes after interrupts were polled, so an IRQ can still
+            // happen right after SEI, and only after the instruction following CLI.
+            Operation::Cli => self.set_interrupt_disable_flag(false),
+            Operation::Sei => self.set_interrupt_disable_flag(true),
+            Operation::Nop => {}
+            _ => unreachable!("{:?} isn't implied", operation),
+        }
     }
 
     #[inline(always)]
-    fn sax(&mut self, address: u16) -> Option<SideEffect> {
-        self.set_memory_value(address, self.a & self.x)
+    fn branch_condition(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Bcc => !self.is_carry_flag_on(),
+            Operation::Bcs => self.is_carry_flag_on(),
+            Operation::Bne => !self.is_zero_flag_on(),
+            Operation::Beq => self.is_zero_flag_on(),
+            Operation::Bvc => !self.is_overflow_flag_on(),
+            Operation::Bvs => self.is_overflow_flag_on(),
+            Operation::Bpl => !self.is_negative_flag_on(),
+            Operation::Bmi => self.is_negative_flag_on(),
+            _ => unreachable!("{:?} isn't a branch", operation),
+        }
     }
 
     #[inline(always)]
-    fn lax(&mut self, value: u8) {
-        self.a = value;
-        self.x = value;
-
+    fn decrement(&mut self, value: u8) -> u8 {
+        let value = value.overflowing_sub(1).0;
         self.toggle_zero_negative_flag(value);
-    }
 
-    #[inline(always)]
-    fn zero_page_address(&self, address: u8, offset: u8) -> u8 {
-        address.overflowing_add(offset).0
+        value
     }
 
     #[inline(always)]
-    fn zero_page_value(&mut self, address: u8, offset: u8) -> u8 {
-        self.bus
-            .read_address(self.zero_page_address(address, offset) as u16)
+    fn increment(&mut self, value: u8) -> u8 {
+        let value = value.overflowing_add(1).0;
+        self.toggle_zero_negative_flag(value);
+
+        value
     }
 
     #[inline(always)]
-    fn absolute_address(&self, address: u16, offset: u8) -> (u16, bool) {
-        let mut address_split = address.to_le_bytes();
-        let (result, carry1) = address_split[0].overflowing_add(offset);
-        address_split[0] = result;
-
-        let (result, carry2) = address_split[1].overflowing_add(carry1 as u8);
-        address_split[1] = result;
-
-        let address = u16::from_le_bytes(address_split);
+    fn lax(&mut self, value: u8) {
+        self.a = value;
+        self.x = value;
 
-        (address, carry1 || carry2)
+        self.toggle_zero_negative_flag(value);
     }
 
     #[inline(always)]
-    fn absolute_value(&mut self, address: u16, offset: u8) -> (u8, bool) {
-        let (address, carry) = self.absolute_address(address, offset);
-
-        (self.bus.read_address(address), carry)
+    fn bit(&mut self, value: u8) {
+        self.set_negative_flag(value & 0x80 != 0);
+        self.set_overflow_flag(value & 0x40 != 0);
+        self.set_zero_flag((value & self.a) == 0);
     }
 
     #[inline(always)]
@@ -1605,14 +812,6 @@ impl Cpu {
         value
     }
 
-    #[inline(always)]
-    #[must_use]
-    fn ror_address(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address);
-        let value = self.ror(value);
-        self.set_memory_value(address, value)
-    }
-
     #[inline(always)]
     fn rol(&mut self, value: u8) -> u8 {
         let mut value = value;
@@ -1624,14 +823,6 @@ impl Cpu {
         value
     }
 
-    #[inline(always)]
-    #[must_use]
-    fn rol_address(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address);
-        let value = self.rol(value);
-        self.set_memory_value(address, value)
-    }
-
     #[inline(always)]
     fn asl(&mut self, value: u8) -> u8 {
         let mut value = value;
@@ -1643,14 +834,6 @@ impl Cpu {
         value
     }
 
-    #[inline(always)]
-    #[must_use]
-    fn asl_address(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address);
-        let value = self.asl(value);
-        self.set_memory_value(address, value)
-    }
-
     #[inline(always)]
     fn lsr(&mut self, value: u8) -> u8 {
         let carry = value & 1 != 0;
@@ -1661,14 +844,6 @@ impl Cpu {
         value
     }
 
-    #[inline(always)]
-    #[must_use]
-    fn lsr_address(&mut self, address: u16) -> Option<SideEffect> {
-        let value = self.bus.read_address(address);
-        let value = self.lsr(value);
-        self.set_memory_value(address, value)
-    }
-
     #[inline(always)]
     fn compare(&mut self, register_value: u8, value: u8) {
         let (value, overflow) = register_value.overflowing_sub(value);
@@ -1711,69 +886,16 @@ impl Cpu {
         self.toggle_zero_negative_flag(self.a);
     }
 
-    #[inline(always)]
-    fn indirect_indexed_address(&mut self, index: u8) -> (u16, bool) {
-        let (low_addr, carry1) = self.bus.read_address(index as u16).overflowing_add(self.y);
-
-        let (high_index, _) = index.overflowing_add(1);
-        let (high_addr, carry2) = self
-            .bus
-            .read_address(high_index as u16)
-            .overflowing_add(carry1 as u8);
-
-        let address = u16::from_le_bytes([low_addr, high_addr]);
-
-        (address, carry1 || carry2)
-    }
-
-    #[inline(always)]
-    fn indirect_indexed_value(&mut self, index: u8) -> (u8, bool) {
-        let (address, overflow) = self.indirect_indexed_address(index);
-
-        (self.bus.read_address(address), overflow)
-    }
-
-    #[inline(always)]
-    fn indexed_indirect_address(&mut self, index: u8) -> u16 {
-        let low_addr = index.overflowing_add(self.x).0;
-        let high_addr = low_addr.overflowing_add(1).0;
-
-        let low_value = self.bus.read_address(low_addr as u16);
-        let high_value = self.bus.read_address(high_addr as u16);
-
-        u16::from_le_bytes([low_value, high_value])
-    }
-
-    #[inline(always)]
-    fn indexed_indirect_value(&mut self, index: u8) -> u8 {
-        let value = self.indexed_indirect_address(index) as u16;
-
-        self.bus.read_address(value)
-    }
-
-    #[inline(always)]
-    fn jump_if(&mut self, condition: bool, offset: u8) -> CpuResult {
-        if condition {
-            let new_address = self.pc as i16 + (offset as i8) as i16;
-            let page_crossed = (self.pc & 0xff00) != ((new_address as u16) & 0xff00);
-
-            self.pc = new_address as u16;
-            return cycles(3 + page_crossed as u32);
-        }
-
-        return cycles(2);
-    }
-
     #[inline(always)]
     fn push(&mut self, value: u8) {
-        self.bus.write_address(self.sp as u16 + 0x0100, value);
+        self.write(self.sp as u16 + 0x0100, value);
         self.sp -= 1;
     }
 
     #[inline(always)]
     fn pop(&mut self) -> u8 {
         self.sp += 1;
-        self.bus.read_address(self.sp as u16 + 0x0100)
+        self.read(self.sp as u16 + 0x0100)
     }
 
     #[inline(always)]
@@ -1786,11 +908,6 @@ impl Cpu {
         self.set_p_flag(1, is_on);
     }
 
-    #[inline(always)]
-    fn set_break_flag(&mut self, is_on: bool) {
-        self.p &= !((is_on as u8) << 4);
-    }
-
     #[inline(always)]
     fn set_carry_flag(&mut self, is_on: bool) {
         self.set_p_flag(0, is_on);
@@ -1870,8 +987,6 @@ impl Cpu {
     pub fn load(mut bus: RealBus) -> Cpu {
         // jump to reset vector
         let reset_vector = u16::from_le_bytes([bus.read_address(0xfffc), bus.read_address(0xfffd)]);
-        let nmi_vector = u16::from_le_bytes([bus.read_address(0xfffa), bus.read_address(0xfffb)]);
-        let irq_vector = u16::from_le_bytes([bus.read_address(0xfffe), bus.read_address(0xffff)]);
 
         Cpu {
             // memory,
@@ -1881,53 +996,112 @@ impl Cpu {
             y: 0,
             p: 0x24,
             sp: 0xfd,
-            nmi_vector,
-            reset_vector,
-            irq_vector,
 
             bus: bus,
-            delayed_i_flag: None,
+
+            nmi_line: false,
+            nmi_pending: false,
+            previous_nmi_pending: false,
+            irq_pending: false,
+            previous_irq_pending: false,
+
+            oam_dma_page: None,
+
+            instruction: None,
+            opcode: 0,
+            micro_ops: [&[], &[]],
+            next_micro_op: 0,
+            servicing_interrupt: false,
+
+            address: 0,
+            pointer: 0,
+            value: 0,
+            page_crossed: false,
         }
     }
 
     pub fn get_memory_buffer(&self) -> &MemoryBuffer {
-        &self.bus.memory
+        &self.bus.ram
     }
 }
 
-impl Iterator for Cpu {
-    type Item = u8;
+impl Snapshot for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.pc);
+        writer.write_u8(self.a);
+        writer.write_u8(self.x);
+        writer.write_u8(self.y);
+        writer.write_u8(self.p);
+        writer.write_u8(self.sp);
+        writer.write_bool(self.nmi_line);
+        writer.write_bool(self.nmi_pending);
+        writer.write_bool(self.previous_nmi_pending);
+        writer.write_bool(self.irq_pending);
+        writer.write_bool(self.previous_irq_pending);
+        writer.write_bool(self.oam_dma_page.is_some());
+        writer.write_u8(self.oam_dma_page.unwrap_or(0));
+
+        self.bus.save_state(writer);
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let value = self.bus.read_address(self.pc);
-        self.pc += 1;
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.pc = reader.read_u16()?;
+        self.a = reader.read_u8()?;
+        self.x = reader.read_u8()?;
+        self.y = reader.read_u8()?;
+        self.p = reader.read_u8()?;
+        self.sp = reader.read_u8()?;
+        self.nmi_line = reader.read_bool()?;
+        self.nmi_pending = reader.read_bool()?;
+        self.previous_nmi_pending = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        self.previous_irq_pending = reader.read_bool()?;
+        let has_oam_dma = reader.read_bool()?;
+        let oam_dma_page = reader.read_u8()?;
+        self.oam_dma_page = if has_oam_dma {
+            Some(oam_dma_page)
+        } else {
+            None
+        };
 
-        return Some(value);
+        self.bus.load_state(reader)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{cell::RefCell, collections::HashSet, rc::Rc};
+    use std::{cell::RefCell, rc::Rc};
 
     use super::*;
-    use crate::{bus::JoypadState, ines::load_cartridge, ppu::Ppu};
+    use crate::{
+        apu::{Apu, MemoryAudioSink},
+        ines::load_cartridge,
+        ppu::{PpuControl, PpuStatus},
+    };
+
+    fn load_nestest() -> Cpu {
+        let cartridge = Rc::new(RefCell::new(load_cartridge("nestest.nes").unwrap()));
+        let bus = RealBus::new(cartridge, Apu::new(Box::new(MemoryAudioSink::new())));
+
+        Cpu::load(bus)
+    }
+
+    // Runs `program` from $0300 in RAM.
+    fn load_program(program: &[u8]) -> Cpu {
+        let mut cpu = load_nestest();
+        cpu.bus.ram[0x300..0x300 + program.len()].copy_from_slice(program);
+        cpu.pc = 0x300;
+
+        cpu
+    }
 
     #[test]
     fn nestest() {
         let text = std::fs::read_to_string("nestest.log").unwrap();
         let lines = text.lines();
 
-        let cartridge = Rc::new(RefCell::new(load_cartridge("nestest.nes").ok().unwrap()));
-        let bus = RealBus {
-            memory: [0; 0x10000],
-            active_buttons: HashSet::new(),
-            joypad_state: JoypadState::Idle,
-            ppu: Ppu::new(crate::ppu::Mirroring::Horizontal, cartridge.clone()),
-            cartridge,
-        };
         let mut cycles = 7;
-        let mut cpu = Cpu::load(bus);
+        let mut cpu = load_nestest();
 
         // starting point according to the nestest guide
         cpu.pc = 0xc000;
@@ -1943,7 +1117,93 @@ mod test {
 
             assert_eq!(cpu_state, trimmed_line);
 
-            cycles += cpu.step().cycles_elapsed;
+            cycles += cpu.step();
+        }
+    }
+
+    #[test]
+    fn oam_dma_copies_page_and_stalls_cpu() {
+        // LDA #$C0, STA $4014, NOP, STA $4014, NOP
+        let mut cpu = load_program(&[0xa9, 0xc0, 0x8d, 0x14, 0x40, 0xea, 0x8d, 0x14, 0x40, 0xea]);
+
+        assert_eq!(cpu.step(), 2);
+        // The copy halts the CPU on the read that follows the write.
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 513 + 2);
+
+        for index in 0..=255u8 {
+            let mut expected = cpu.bus.read_address(0xc000 + index as u16);
+            if index & 3 == 2 {
+                expected &= 0xe3;
+            }
+            cpu.bus.ppu.set_oam_address(index);
+            assert_eq!(cpu.bus.ppu.read_oam_data(), expected);
+        }
+
+        // The second copy would start on an odd cycle.
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.step(), 514 + 2);
+    }
+
+    #[test]
+    fn dmc_fetch_stalls_cpu_and_raises_irq() {
+        // Whether the opcode read would be an even cycle, and the cycles the fetch adds.
+        let cases = [(true, 3), (false, 4)];
+
+        for (starts_on_even_cycle, stall_cycles) in cases.iter() {
+            // NOP
+            let mut cpu = load_program(&[0xea]);
+            if ((cpu.bus.cycle() + 1) & 1 == 0) != *starts_on_even_cycle {
+                cpu.bus.tick();
+            }
+
+            // Play a one byte sample from $C000 and raise an IRQ when it's done.
+            cpu.bus.write_address(0x4017, 0x40);
+            cpu.bus.write_address(0x4010, 0x8f);
+            cpu.bus.write_address(0x4012, 0);
+            cpu.bus.write_address(0x4013, 0);
+            cpu.bus.write_address(0x4015, 0x10);
+
+            // The fetch halts the CPU on the opcode read.
+            assert_eq!(cpu.step(), 2 + stall_cycles);
+            assert!(cpu.bus.has_pending_irq());
+            assert_eq!(cpu.bus.read_address(0x4015) & 0x80, 0x80);
+
+            cpu.bus.write_address(0x4015, 0);
+            assert!(!cpu.bus.has_pending_irq());
+        }
+    }
+
+    #[test]
+    fn reading_status_as_vblank_starts_suppresses_nmi() {
+        // The dot the PPU is at when LDA $2002 starts, whether it reads the vblank
+        // flag, and whether an NMI follows. The read happens 12 dots later, and
+        // vblank starts at dot 1 of line 241.
+        let cases = [(329, false, true), (330, false, false), (331, true, false)];
+
+        for (start_dot, reads_vblank, takes_nmi) in cases.iter() {
+            // LDA $2002, NOP, NOP
+            let mut cpu = load_program(&[0xad, 0x02, 0x20, 0xea, 0xea]);
+            cpu.bus.ppu.set_control(PpuControl::GENERATE_NMI_AT_VBLANK);
+
+            while !(cpu.bus.ppu.get_current_scanline() == 240
+                && cpu.bus.ppu.get_current_dot() == *start_dot)
+            {
+                cpu.bus.ppu.step();
+            }
+
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.a & 0x80 != 0, *reads_vblank);
+            assert!(!cpu.bus.ppu.get_status().contains(PpuStatus::IN_VBLANK));
+
+            cpu.step();
+            cpu.step();
+
+            let nmi_vector = u16::from_le_bytes([
+                cpu.bus.read_address(NMI_VECTOR),
+                cpu.bus.read_address(NMI_VECTOR + 1),
+            ]);
+            assert_eq!(cpu.pc == nmi_vector, *takes_nmi);
         }
     }
 }
@@ -1,17 +1,168 @@
 use std::ops::{Shl, Shr};
 
-use crate::ppu::Mirroring;
+use crate::{
+    apu::{ExpansionAudio, Mmc5Audio, Vrc6Audio},
+    ppu::{Mirroring, NametableLayout, NametableSource},
+    save_state::{rom_checksum, SaveStateError, Snapshot, StateReader, StateWriter},
+};
 
 fn prg_bank_size(bytes: &[u8]) -> usize {
     bytes.len() / 0x4000
 }
 
-pub trait Mapper {
+/// Work RAM mapped at $6000-$7FFF. It is owned by the cartridge so that every
+/// mapper shares the same battery save handling.
+pub struct PrgRam {
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+impl PrgRam {
+    fn new(size: usize) -> PrgRam {
+        PrgRam {
+            data: vec![0; size],
+            dirty: false,
+        }
+    }
+
+    /// Returns `None` when the cartridge has no PRG-RAM.
+    pub fn read(&self, address: u16) -> Option<u8> {
+        self.read_offset(address as usize - 0x6000)
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.write_offset(address as usize - 0x6000, value)
+    }
+
+    /// Like `read`, but takes an offset into the RAM, for mappers that bank it.
+    pub fn read_offset(&self, offset: usize) -> Option<u8> {
+        if self.data.is_empty() {
+            None
+        } else {
+            Some(self.data[offset % self.data.len()])
+        }
+    }
+
+    pub fn write_offset(&mut self, offset: usize, value: u8) {
+        if self.data.is_empty() {
+            return;
+        }
+
+        let index = offset % self.data.len();
+        if self.data[index] != value {
+            self.data[index] = value;
+            self.dirty = true;
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replaces the contents with a previously saved image, e.g. a `.sav` file.
+    /// Images of a different size are copied as far as they fit.
+    pub fn load(&mut self, bytes: &[u8]) {
+        let length = bytes.len().min(self.data.len());
+        self.data[..length].copy_from_slice(&bytes[..length]);
+    }
+
+    /// Whether the contents changed since the last `clear_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+}
+
+impl Snapshot for PrgRam {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        let mut data = vec![0; self.data.len()];
+        reader.read_bytes(&mut data)?;
+        if data != self.data {
+            self.data = data;
+            self.dirty = true;
+        }
+
+        Ok(())
+    }
+}
+
+pub trait Mapper: Snapshot {
     fn write_address(&mut self, prg_rom: &[u8], address: u16, value: u8);
     fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8;
-    fn read_chr_rom(&self, chr_rom: &[u8], address: u16) -> Option<u8>;
-    fn mirroring(&self) -> Option<Mirroring>;
-    fn scanline_tick(&mut self);
+
+    /// Reads from $6000-$7FFF. `None` means nothing answers, so the CPU sees open bus.
+    fn read_prg_ram(&mut self, prg_ram: &PrgRam, address: u16) -> Option<u8> {
+        prg_ram.read(address)
+    }
+
+    fn write_prg_ram(&mut self, prg_ram: &mut PrgRam, address: u16, value: u8) {
+        prg_ram.write(address, value)
+    }
+
+    /// Maps a PPU address in $0000-$1FFF to an offset into CHR ROM or RAM.
+    fn chr_address(&self, address: u16) -> usize;
+
+    fn read_chr(&self, chr: &[u8], address: u16) -> u8 {
+        chr[self.chr_address(address) % chr.len()]
+    }
+
+    fn write_chr(&mut self, chr: &mut [u8], address: u16, value: u8) {
+        chr[self.chr_address(address) % chr.len()] = value;
+    }
+
+    /// Called after the PPU fetched a pattern byte while rendering or through $2007,
+    /// for mappers that switch banks by watching the PPU bus.
+    fn chr_fetched(&mut self, _address: u16) {}
+
+    /// Reads from $4020-$5FFF, where a few mappers put extra registers or RAM.
+    /// `None` means nothing answers, so the CPU sees open bus.
+    fn read_expansion(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn write_expansion(&mut self, _address: u16, _value: u8) {}
+
+    /// Lets the mapper see CPU writes to the PPU registers at $2000-$2007.
+    fn ppu_register_written(&mut self, _address: u16, _value: u8) {}
+
+    /// Called at the start of every scanline, for mappers that count scanlines
+    /// by watching the PPU bus rather than through A12.
+    fn scanline_started(&mut self, _scanline: u32, _rendering: bool) {}
+
+    /// Reads the nametable slots that `nametable_layout` gives to `NametableSource::Mapper`.
+    fn read_nametable(&self, _address: u16) -> u8 {
+        0
+    }
+
+    fn write_nametable(&mut self, _address: u16, _value: u8) {}
+
+    /// Called after the PPU fetched a nametable byte while rendering or through $2007.
+    /// Returns the byte the PPU gets, which lets the mapper substitute its own.
+    fn nametable_fetched(&mut self, _address: u16, value: u8) -> u8 {
+        value
+    }
+
+    /// Called when PPU A12 rises after having been low for a while, which is how
+    /// MMC3 counts scanlines.
+    fn a12_rising_edge(&mut self) {}
+
+    /// Called once per CPU cycle.
+    fn cpu_tick(&mut self) {}
+
+    /// The cartridge's own sound chip. The APU clocks it and mixes in its output.
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        None
+    }
+
+    /// Where each nametable slot comes from. `None` leaves it to the header's mirroring.
+    fn nametable_layout(&self) -> Option<NametableLayout>;
     fn has_pending_irq(&self) -> bool;
 }
 
@@ -40,32 +191,36 @@ impl Mapper for CNROM {
         }
     }
 
-    fn read_chr_rom(&self, chr_rom: &[u8], address: u16) -> Option<u8> {
-        if chr_rom.is_empty() {
-            None
-        } else {
-            Some(chr_rom[self.chr_bank * 0x2000 + address as usize])
-        }
+    fn chr_address(&self, address: u16) -> usize {
+        self.chr_bank * 0x2000 + address as usize
     }
 
-    fn mirroring(&self) -> Option<Mirroring> {
+    fn nametable_layout(&self) -> Option<NametableLayout> {
         None
     }
 
-    fn scanline_tick(&mut self) {}
-
     fn has_pending_irq(&self) -> bool {
         false
     }
 }
 
+impl Snapshot for CNROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.chr_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.chr_bank = reader.read_usize()?;
+        Ok(())
+    }
+}
+
 struct SNROM {
     shift_register: u8,
     control: u8,
     chr_bank_0: u8,
     chr_bank_1: u8,
     prg_bank: u8,
-    prg_ram: [u8; 0x2000],
 }
 
 impl SNROM {
@@ -76,7 +231,6 @@ impl SNROM {
             chr_bank_0: 0,
             chr_bank_1: 0,
             prg_bank: 0,
-            prg_ram: [0; 0x2000],
         }
     }
 
@@ -98,11 +252,6 @@ impl SNROM {
 
 impl Mapper for SNROM {
     fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
-        if address >= 0x6000 && address < 0x8000 {
-            self.prg_ram[address as usize - 0x6000] = value;
-            return;
-        }
-
         // println!("Write MMC1: {:#010b} at {:#06X}", value, address);
         if value & 0x80 != 0 {
             self.shift_register = 0b10000;
@@ -130,11 +279,6 @@ impl Mapper for SNROM {
     }
 
     fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
-        if address >= 0x6000 && address < 0x8000 {
-            // println!("prg read at {:#06X}", address);
-            return self.prg_ram[address as usize - 0x6000];
-        }
-
         let bank_size = prg_bank_size(prg_rom);
 
         match (self.control & 0b1100) >> 2 {
@@ -162,39 +306,62 @@ impl Mapper for SNROM {
         }
     }
 
-    fn read_chr_rom(&self, chr_rom: &[u8], address: u16) -> Option<u8> {
-        if chr_rom.is_empty() {
-            return None;
-        }
-
+    fn chr_address(&self, address: u16) -> usize {
         let (bank1, bank2) = self.bank_addresses();
 
-        Some(match address {
-            0..=0x0fff => chr_rom[bank1 + address as usize],
-            0x1000..=0x1fff => chr_rom[bank2 - 0x1000 + address as usize],
+        match address {
+            0..=0x0fff => bank1 + address as usize,
+            0x1000..=0x1fff => bank2 + address as usize - 0x1000,
             _ => panic!("CHR address out of range: {:#06X}", address),
-        })
+        }
     }
 
-    fn mirroring(&self) -> Option<Mirroring> {
-        match self.control & 0b11 {
-            2 => Some(Mirroring::Vertical),
-            3 => Some(Mirroring::Horizontal),
-            0 => Some(Mirroring::OneScreenLow),
-            1 => Some(Mirroring::OneScreenHigh),
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        let mirroring = match self.control & 0b11 {
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            0 => Mirroring::OneScreenLow,
+            1 => Mirroring::OneScreenHigh,
             _ => panic!("Unsupported mirror: {:#04X}", self.control),
-        }
-    }
+        };
 
-    fn scanline_tick(&mut self) {}
+        Some(mirroring.layout())
+    }
 
     fn has_pending_irq(&self) -> bool {
         false
     }
 }
 
+impl Snapshot for SNROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.shift_register);
+        writer.write_u8(self.control);
+        writer.write_u8(self.chr_bank_0);
+        writer.write_u8(self.chr_bank_1);
+        writer.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift_register = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.chr_bank_0 = reader.read_u8()?;
+        self.chr_bank_1 = reader.read_u8()?;
+        self.prg_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
 struct NROM;
 
+impl Snapshot for NROM {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
+
 impl Mapper for NROM {
     fn write_address(&mut self, _prg_rom: &[u8], _address: u16, _value: u8) {
         // ignore
@@ -212,20 +379,14 @@ impl Mapper for NROM {
         }
     }
 
-    fn read_chr_rom(&self, chr_rom: &[u8], address: u16) -> Option<u8> {
-        if chr_rom.len() != 0 {
-            Some(chr_rom[address as usize])
-        } else {
-            None
-        }
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize
     }
 
-    fn mirroring(&self) -> Option<Mirroring> {
+    fn nametable_layout(&self) -> Option<NametableLayout> {
         None
     }
 
-    fn scanline_tick(&mut self) {}
-
     fn has_pending_irq(&self) -> bool {
         false
     }
@@ -260,185 +421,1309 @@ impl Mapper for UNROM {
         }
     }
 
-    fn read_chr_rom(&self, _chr_rom: &[u8], _address: u16) -> Option<u8> {
-        None
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize
     }
 
-    fn mirroring(&self) -> Option<Mirroring> {
+    fn nametable_layout(&self) -> Option<NametableLayout> {
         None
     }
 
-    fn scanline_tick(&mut self) {}
-
     fn has_pending_irq(&self) -> bool {
         false
     }
 }
 
-struct TxROM {
-    bank_select: u8,
-    r: [u8; 8],
+impl Snapshot for UNROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.current_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+struct AxROM {
+    prg_bank: u8,
     mirroring: Mirroring,
-    irq_reload_value: u8,
-    irq_reset: bool,
-    irq_enabled: bool,
-    current_irq_counter: u8,
-    has_pending_irq: bool,
 }
 
-impl TxROM {
-    fn new() -> TxROM {
-        TxROM {
-            bank_select: 0,
-            r: [0; 8],
-            mirroring: Mirroring::Vertical,
-            irq_reload_value: 0,
-            irq_reset: false,
-            irq_enabled: false,
-            current_irq_counter: 0,
-            has_pending_irq: false,
+impl AxROM {
+    fn new() -> AxROM {
+        AxROM {
+            prg_bank: 0,
+            mirroring: Mirroring::OneScreenLow,
         }
     }
+}
 
-    fn toggle_pending_irq_if_possible(&mut self) {
-        if self.current_irq_counter == 0 && self.irq_enabled {
-            self.has_pending_irq = true;
+impl Mapper for AxROM {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = value & 0b111;
+            self.mirroring = if value & 0x10 == 0 {
+                Mirroring::OneScreenLow
+            } else {
+                Mirroring::OneScreenHigh
+            };
         }
     }
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let mapped_address = address as usize - 0x8000 + self.prg_bank as usize * 0x8000;
+        prg_rom[mapped_address % prg_rom.len()]
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        Some(self.mirroring.layout())
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        false
+    }
 }
 
-impl Mapper for TxROM {
-    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
-        // if address >= 0xc000 {
-        //     println!("TxROM write {:#06X}: {:#04X}", address, value);
-        // }
-        let is_odd = address % 2 != 0;
+impl Snapshot for AxROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank);
+        writer.write_bool(self.mirroring == Mirroring::OneScreenHigh);
+    }
 
-        match (address, is_odd) {
-            (0x8000..=0x9fff, false) => {
-                self.bank_select = value;
-            }
-            (0x8000..=0x9fff, true) => {
-                let r_index = self.bank_select & 0b111;
-                self.r[r_index as usize] = if r_index >= 6 {
-                    value & 0b00111111
-                } else if r_index <= 1 {
-                    value & 0b11111110
-                } else {
-                    value
-                };
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_bank = reader.read_u8()?;
+        self.mirroring = if reader.read_bool()? {
+            Mirroring::OneScreenHigh
+        } else {
+            Mirroring::OneScreenLow
+        };
+        Ok(())
+    }
+}
 
-                // println!("Write bank {}: {:#04X}", r_index, value);
-            }
-            (0xa000..=0xbfff, false) => {
+/// The CHR side shared by MMC2 and MMC4: each pattern table has two banks,
+/// and which one is used flips when the PPU fetches tile $FD or $FE.
+struct ChrLatches {
+    banks: [[u8; 2]; 2],
+    latches: [usize; 2],
+    mirroring: Mirroring,
+}
+
+impl ChrLatches {
+    fn new() -> ChrLatches {
+        ChrLatches {
+            banks: [[0; 2]; 2],
+            latches: [1, 1],
+            mirroring: Mirroring::Vertical,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xb000..=0xbfff => self.banks[0][0] = value & 0x1f,
+            0xc000..=0xcfff => self.banks[0][1] = value & 0x1f,
+            0xd000..=0xdfff => self.banks[1][0] = value & 0x1f,
+            0xe000..=0xefff => self.banks[1][1] = value & 0x1f,
+            0xf000..=0xffff => {
                 self.mirroring = if value & 1 == 0 {
                     Mirroring::Vertical
                 } else {
                     Mirroring::Horizontal
                 }
             }
-            (0xc000..=0xdfff, false) => self.irq_reload_value = value,
-            (0xc000..=0xdfff, true) => {
-                self.irq_reset = true;
-                // println!("Reset irq counter");
-            }
-            (0xe000..=0xffff, false) => {
-                // println!("disable IRQ");
-                self.irq_enabled = false;
-                self.has_pending_irq = false;
-            }
-            (0xe000..=0xffff, true) => {
-                self.irq_enabled = true;
-                // println!("enable IRQ");
-            }
-
             _ => {}
         }
     }
 
-    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
-        let prg_flag = self.bank_select & 0x40 != 0;
-        let prg_size = prg_rom.len() / 0x2000;
+    fn chr_address(&self, address: u16) -> usize {
+        let table = (address as usize >> 12) & 1;
+        let bank = self.banks[table][self.latches[table]] as usize;
 
-        let mapped_address: usize = match (address, prg_flag) {
-            (0x8000..=0x9fff, false) => address as usize - 0x8000 + self.r[6] as usize * 0x2000,
-            (0x8000..=0x9fff, true) => address as usize - 0x8000 + (prg_size - 2) as usize * 0x2000,
-            (0xa000..=0xbfff, _) => address as usize - 0xa000 + self.r[7] as usize * 0x2000,
-            (0xc000..=0xdfff, false) => {
-                address as usize - 0xc000 + (prg_size - 2) as usize * 0x2000
-            }
-            (0xc000..=0xdfff, true) => address as usize - 0xc000 + self.r[6] as usize * 0x2000,
-            (0xe000..=0xffff, _) => address as usize - 0xe000 + (prg_size - 1) as usize * 0x2000,
-            _ => panic!("Unhandled address: {:#06X}", address),
+        bank * 0x1000 + (address as usize & 0xfff)
+    }
+
+    // MMC2 only watches a single address for the left pattern table, MMC4 a whole tile row.
+    fn update(&mut self, address: u16, exact_left_table: bool) {
+        let table = (address as usize >> 12) & 1;
+        let offset = address & 0xfff;
+        let row = if table == 0 && exact_left_table {
+            offset
+        } else {
+            offset & 0xff8
         };
 
-        prg_rom[mapped_address as usize]
+        match row {
+            0xfd8 => self.latches[table] = 0,
+            0xfe8 => self.latches[table] = 1,
+            _ => {}
+        }
     }
+}
 
-    fn read_chr_rom(&self, chr_rom: &[u8], address: u16) -> Option<u8> {
-        let chr_flag = self.bank_select & 0x80 != 0;
-        let chr_bank_size = 0x400;
+impl Snapshot for ChrLatches {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for banks in &self.banks {
+            writer.write_bytes(banks);
+        }
+        writer.write_u8(self.latches[0] as u8);
+        writer.write_u8(self.latches[1] as u8);
+        writer.write_bool(self.mirroring == Mirroring::Horizontal);
+    }
 
-        if chr_rom.len() == 0 {
-            None
-        } else {
-            let address = address as usize;
-            let mapped_address: usize = match (address, chr_flag) {
-                (0x0000..=0x07ff, false) => address + self.r[0] as usize * chr_bank_size,
-                (0x0800..=0x0fff, false) => address - 0x0800 + self.r[1] as usize * chr_bank_size,
-                (0x1000..=0x13ff, false) => address - 0x1000 + self.r[2] as usize * chr_bank_size,
-                (0x1400..=0x17ff, false) => address - 0x1400 + self.r[3] as usize * chr_bank_size,
-                (0x1800..=0x1bff, false) => address - 0x1800 + self.r[4] as usize * chr_bank_size,
-                (0x1c00..=0x1fff, false) => address - 0x1c00 + self.r[5] as usize * chr_bank_size,
-
-                (0x0000..=0x03ff, true) => address + self.r[2] as usize * chr_bank_size,
-                (0x0400..=0x07ff, true) => address - 0x0400 + self.r[3] as usize * chr_bank_size,
-                (0x0800..=0x0bff, true) => address - 0x0800 + self.r[4] as usize * chr_bank_size,
-                (0x0c00..=0x0fff, true) => address - 0x0c00 + self.r[5] as usize * chr_bank_size,
-                (0x1000..=0x17ff, true) => address - 0x1000 + self.r[0] as usize * chr_bank_size,
-                (0x1800..=0x1fff, true) => address - 0x1800 + self.r[1] as usize * chr_bank_size,
-                _ => panic!("Unhandled address: {:#06X}", address),
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        for banks in &mut self.banks {
+            reader.read_bytes(banks)?;
+        }
+        for latch in &mut self.latches {
+            *latch = match reader.read_u8()? {
+                0 => 0,
+                1 => 1,
+                _ => return Err(SaveStateError::InvalidValue),
             };
+        }
+        self.mirroring = if reader.read_bool()? {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+        Ok(())
+    }
+}
 
-            Some(chr_rom[mapped_address])
+/// MMC2 (mapper 9): an 8 KiB switchable PRG bank followed by the last three fixed.
+struct PxROM {
+    prg_bank: u8,
+    chr: ChrLatches,
+}
+
+impl PxROM {
+    fn new() -> PxROM {
+        PxROM {
+            prg_bank: 0,
+            chr: ChrLatches::new(),
         }
     }
+}
 
-    fn mirroring(&self) -> Option<Mirroring> {
-        Some(self.mirroring)
+impl Mapper for PxROM {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        match address {
+            0xa000..=0xafff => self.prg_bank = value & 0xf,
+            _ => self.chr.write_register(address, value),
+        }
     }
 
-    fn scanline_tick(&mut self) {
-        if self.irq_reset {
-            self.current_irq_counter = self.irq_reload_value;
-            self.irq_reset = false;
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let prg_size = prg_rom.len() / 0x2000;
 
-            self.toggle_pending_irq_if_possible();
-            // println!("Reload counter to {}", self.current_irq_counter);
-        } else {
-            if self.current_irq_counter == 0 {
-                self.current_irq_counter = self.irq_reload_value;
+        let mapped_address = match address {
+            0x8000..=0x9fff => address as usize - 0x8000 + self.prg_bank as usize * 0x2000,
+            0xa000..=0xffff => address as usize - 0xa000 + (prg_size - 3) * 0x2000,
+            _ => panic!("Unhandled address: {:#06X}", address),
+        };
 
-                self.toggle_pending_irq_if_possible();
-            } else {
-                if self.current_irq_counter > 0 {
-                    self.current_irq_counter -= 1;
-                }
-                // dbg!(self.current_irq_counter);
-                self.toggle_pending_irq_if_possible();
-            }
-        }
+        prg_rom[mapped_address % prg_rom.len()]
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        self.chr.chr_address(address)
+    }
+
+    fn chr_fetched(&mut self, address: u16) {
+        self.chr.update(address, true);
+    }
+
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        Some(self.chr.mirroring.layout())
     }
 
     fn has_pending_irq(&self) -> bool {
-        self.has_pending_irq
+        false
     }
 }
 
+impl Snapshot for PxROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank);
+        self.chr.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_bank = reader.read_u8()?;
+        self.chr.load_state(reader)
+    }
+}
+
+/// MMC4 (mapper 10): a 16 KiB switchable PRG bank followed by the last one fixed.
+struct FxROM {
+    prg_bank: u8,
+    chr: ChrLatches,
+}
+
+impl FxROM {
+    fn new() -> FxROM {
+        FxROM {
+            prg_bank: 0,
+            chr: ChrLatches::new(),
+        }
+    }
+}
+
+impl Mapper for FxROM {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        match address {
+            0xa000..=0xafff => self.prg_bank = value & 0xf,
+            _ => self.chr.write_register(address, value),
+        }
+    }
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let bank_size = prg_bank_size(prg_rom);
+
+        let mapped_address = match address {
+            0x8000..=0xbfff => address as usize - 0x8000 + self.prg_bank as usize * 0x4000,
+            0xc000..=0xffff => address as usize - 0xc000 + (bank_size - 1) * 0x4000,
+            _ => panic!("Unhandled address: {:#06X}", address),
+        };
+
+        prg_rom[mapped_address % prg_rom.len()]
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        self.chr.chr_address(address)
+    }
+
+    fn chr_fetched(&mut self, address: u16) {
+        self.chr.update(address, false);
+    }
+
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        Some(self.chr.mirroring.layout())
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        false
+    }
+}
+
+impl Snapshot for FxROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank);
+        self.chr.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_bank = reader.read_u8()?;
+        self.chr.load_state(reader)
+    }
+}
+
+/// MMC5 (mapper 5). The vertical split mode and PRG-RAM banked into
+/// $8000-$DFFF are not supported.
+struct ExROM {
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    prg_ram_bank: u8,
+    prg_banks: [u8; 4],
+    chr_banks: [u16; 12],
+    chr_upper_bits: u8,
+    last_wrote_background_chr: bool,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline_counter: u8,
+    multiplicand: u8,
+    multiplier: u8,
+    exram: [u8; 0x400],
+    large_sprites: bool,
+    // Pattern fetches left for the background tile whose nametable entry was read last.
+    background_fetches: u8,
+    extended_attribute: u8,
+    audio: Mmc5Audio,
+}
+
+impl ExROM {
+    fn new() -> ExROM {
+        ExROM {
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_ram_bank: 0,
+            prg_banks: [0, 0, 0, 0xff],
+            chr_banks: [0; 12],
+            chr_upper_bits: 0,
+            last_wrote_background_chr: false,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+            exram: [0; 0x400],
+            large_sprites: false,
+            background_fetches: 0,
+            extended_attribute: 0,
+            audio: Mmc5Audio::new(),
+        }
+    }
+
+    fn nametable_slot(&self, address: u16) -> u8 {
+        (self.nametable_mapping >> (((address >> 10) & 3) * 2)) & 3
+    }
+
+    fn is_extended_attribute_mode(&self) -> bool {
+        self.exram_mode == 1
+    }
+
+    // With 8x16 sprites, background and sprites have separate CHR banks ($5128-$512B and
+    // $5120-$5127). Otherwise, and outside rendering, the set written last is used.
+    fn uses_background_chr(&self) -> bool {
+        if self.large_sprites && self.in_frame {
+            self.background_fetches > 0
+        } else {
+            self.last_wrote_background_chr
+        }
+    }
+}
+
+impl Mapper for ExROM {
+    fn write_address(&mut self, _prg_rom: &[u8], _address: u16, _value: u8) {}
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let slot = (address as usize - 0x8000) / 0x2000;
+
+        let bank = match (self.prg_mode, slot) {
+            (0, _) => (self.prg_banks[3] as usize & !3) + slot,
+            (1, 0..=1) | (2, 0..=1) => (self.prg_banks[1] as usize & !1) + slot,
+            (1, _) => (self.prg_banks[3] as usize & !1) + slot - 2,
+            _ => self.prg_banks[slot] as usize,
+        } & 0x7f;
+
+        prg_rom[(bank * 0x2000 + (address as usize & 0x1fff)) % prg_rom.len()]
+    }
+
+    fn read_prg_ram(&mut self, prg_ram: &PrgRam, address: u16) -> Option<u8> {
+        prg_ram.read_offset(self.prg_ram_bank as usize * 0x2000 + address as usize - 0x6000)
+    }
+
+    fn write_prg_ram(&mut self, prg_ram: &mut PrgRam, address: u16, value: u8) {
+        if self.prg_ram_protect == [2, 1] {
+            prg_ram.write_offset(
+                self.prg_ram_bank as usize * 0x2000 + address as usize - 0x6000,
+                value,
+            )
+        }
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let address = address as usize & 0x1fff;
+
+        if self.is_extended_attribute_mode() && self.background_fetches > 0 {
+            let bank =
+                (self.extended_attribute & 0x3f) as usize | (self.chr_upper_bits as usize) << 6;
+            return bank * 0x1000 + (address & 0xfff);
+        }
+
+        let bank_size = 0x2000 >> self.chr_mode;
+        let register = if self.uses_background_chr() {
+            // Only four registers, repeated in both pattern tables.
+            let step = (8 >> self.chr_mode).min(4);
+            8 + (address & 0xfff) / bank_size * step + step - 1
+        } else {
+            let step = 8 >> self.chr_mode;
+            address / bank_size * step + step - 1
+        };
+
+        self.chr_banks[register] as usize * bank_size + address % bank_size
+    }
+
+    fn chr_fetched(&mut self, _address: u16) {
+        if self.background_fetches > 0 {
+            self.background_fetches -= 1;
+        }
+    }
+
+    fn read_expansion(&mut self, address: u16) -> Option<u8> {
+        let product = self.multiplicand as u16 * self.multiplier as u16;
+
+        match address {
+            0x5015 => Some(self.audio.read_status()),
+            0x5204 => {
+                let status = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                Some(status)
+            }
+            0x5205 => Some(product as u8),
+            0x5206 => Some((product >> 8) as u8),
+            0x5c00..=0x5fff if self.exram_mode >= 2 => Some(self.exram[address as usize - 0x5c00]),
+            _ => None,
+        }
+    }
+
+    fn write_expansion(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5015 => self.audio.write_register(address, value),
+            0x5100 => self.prg_mode = value & 3,
+            0x5101 => self.chr_mode = value & 3,
+            0x5102 => self.prg_ram_protect[0] = value & 3,
+            0x5103 => self.prg_ram_protect[1] = value & 3,
+            0x5104 => self.exram_mode = value & 3,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 3,
+            0x5113 => self.prg_ram_bank = value & 7,
+            0x5114..=0x5117 => self.prg_banks[address as usize - 0x5114] = value,
+            0x5120..=0x512b => {
+                self.chr_banks[address as usize - 0x5120] =
+                    value as u16 | (self.chr_upper_bits as u16) << 8;
+                self.last_wrote_background_chr = address >= 0x5128;
+            }
+            0x5130 => self.chr_upper_bits = value & 3,
+            0x5203 => self.irq_compare = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            0x5c00..=0x5fff if self.exram_mode != 3 => {
+                self.exram[address as usize - 0x5c00] = value
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_register_written(&mut self, address: u16, value: u8) {
+        match address {
+            0x2000 => self.large_sprites = value & 0x20 != 0,
+            0x2001 if value & 0x18 == 0 => self.in_frame = false,
+            _ => {}
+        }
+    }
+
+    fn scanline_started(&mut self, scanline: u32, rendering: bool) {
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline_counter = 0;
+        } else {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.irq_compare {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn read_nametable(&self, address: u16) -> u8 {
+        let offset = address as usize & 0x3ff;
+
+        match self.nametable_slot(address) {
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            2 => 0,
+            _ if offset >= 0x3c0 => self.fill_attribute * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8) {
+        if self.nametable_slot(address) == 2 && self.exram_mode <= 1 {
+            self.exram[address as usize & 0x3ff] = value;
+        }
+    }
+
+    fn nametable_fetched(&mut self, address: u16, value: u8) -> u8 {
+        let offset = address as usize & 0x3ff;
+        let is_attribute = offset >= 0x3c0;
+
+        if !is_attribute {
+            self.background_fetches = 2;
+        }
+
+        // Extended attributes give every tile its own palette and 4 KiB CHR bank.
+        if !self.is_extended_attribute_mode() {
+            value
+        } else if is_attribute {
+            (self.extended_attribute >> 6) * 0x55
+        } else {
+            self.extended_attribute = self.exram[offset];
+            value
+        }
+    }
+
+    // Slots 0 and 1 select a CIRAM page, 2 is ExRAM and 3 is fill mode.
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        let mut layout = [NametableSource::Mapper; 4];
+
+        for (slot, source) in layout.iter_mut().enumerate() {
+            let page = (self.nametable_mapping >> (slot * 2)) & 3;
+            if page < 2 {
+                *source = NametableSource::Ciram(page);
+            }
+        }
+
+        Some(layout)
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Snapshot for ExROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_mode);
+        writer.write_u8(self.chr_mode);
+        writer.write_bytes(&self.prg_ram_protect);
+        writer.write_u8(self.exram_mode);
+        writer.write_u8(self.nametable_mapping);
+        writer.write_u8(self.fill_tile);
+        writer.write_u8(self.fill_attribute);
+        writer.write_u8(self.prg_ram_bank);
+        writer.write_bytes(&self.prg_banks);
+        for bank in &self.chr_banks {
+            writer.write_u16(*bank);
+        }
+        writer.write_u8(self.chr_upper_bits);
+        writer.write_bool(self.last_wrote_background_chr);
+        writer.write_u8(self.irq_compare);
+        writer.write_bool(self.irq_enabled);
+        writer.write_bool(self.irq_pending);
+        writer.write_bool(self.in_frame);
+        writer.write_u8(self.scanline_counter);
+        writer.write_u8(self.multiplicand);
+        writer.write_u8(self.multiplier);
+        writer.write_bytes(&self.exram);
+        writer.write_bool(self.large_sprites);
+        writer.write_u8(self.background_fetches);
+        writer.write_u8(self.extended_attribute);
+        self.audio.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_mode = reader.read_u8()?;
+        self.chr_mode = reader.read_u8()?;
+        reader.read_bytes(&mut self.prg_ram_protect)?;
+        self.exram_mode = reader.read_u8()?;
+        self.nametable_mapping = reader.read_u8()?;
+        self.fill_tile = reader.read_u8()?;
+        self.fill_attribute = reader.read_u8()?;
+        self.prg_ram_bank = reader.read_u8()?;
+        reader.read_bytes(&mut self.prg_banks)?;
+        for bank in &mut self.chr_banks {
+            *bank = reader.read_u16()?;
+        }
+        self.chr_upper_bits = reader.read_u8()?;
+        self.last_wrote_background_chr = reader.read_bool()?;
+        self.irq_compare = reader.read_u8()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.irq_pending = reader.read_bool()?;
+        self.in_frame = reader.read_bool()?;
+        self.scanline_counter = reader.read_u8()?;
+        self.multiplicand = reader.read_u8()?;
+        self.multiplier = reader.read_u8()?;
+        reader.read_bytes(&mut self.exram)?;
+        self.large_sprites = reader.read_bool()?;
+        self.background_fetches = reader.read_u8()?;
+        self.extended_attribute = reader.read_u8()?;
+        self.audio.load_state(reader)
+    }
+}
+
+struct TxROM {
+    bank_select: u8,
+    r: [u8; 8],
+    mirroring: Mirroring,
+    irq_reload_value: u8,
+    irq_reset: bool,
+    irq_enabled: bool,
+    current_irq_counter: u8,
+    has_pending_irq: bool,
+    prg_ram_enabled: bool,
+    prg_ram_write_protected: bool,
+    revision_a: bool,
+}
+
+impl TxROM {
+    fn new(revision_a: bool) -> TxROM {
+        TxROM {
+            bank_select: 0,
+            r: [0; 8],
+            mirroring: Mirroring::Vertical,
+            irq_reload_value: 0,
+            irq_reset: false,
+            irq_enabled: false,
+            current_irq_counter: 0,
+            has_pending_irq: false,
+            // Plenty of games never write $A001, so start with the RAM usable.
+            prg_ram_enabled: true,
+            prg_ram_write_protected: false,
+            revision_a,
+        }
+    }
+
+    fn toggle_pending_irq_if_possible(&mut self) {
+        if self.current_irq_counter == 0 && self.irq_enabled {
+            self.has_pending_irq = true;
+        }
+    }
+}
+
+impl Mapper for TxROM {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        // if address >= 0xc000 {
+        //     println!("TxROM write {:#06X}: {:#04X}", address, value);
+        // }
+        let is_odd = address % 2 != 0;
+
+        match (address, is_odd) {
+            (0x8000..=0x9fff, false) => {
+                self.bank_select = value;
+            }
+            (0x8000..=0x9fff, true) => {
+                let r_index = self.bank_select & 0b111;
+                self.r[r_index as usize] = if r_index >= 6 {
+                    value & 0b00111111
+                } else if r_index <= 1 {
+                    value & 0b11111110
+                } else {
+                    value
+                };
+
+                // println!("Write bank {}: {:#04X}", r_index, value);
+            }
+            (0xa000..=0xbfff, false) => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                }
+            }
+            (0xa000..=0xbfff, true) => {
+                self.prg_ram_enabled = value & 0x80 != 0;
+                self.prg_ram_write_protected = value & 0x40 != 0;
+            }
+            (0xc000..=0xdfff, false) => self.irq_reload_value = value,
+            (0xc000..=0xdfff, true) => {
+                self.irq_reset = true;
+                // println!("Reset irq counter");
+            }
+            (0xe000..=0xffff, false) => {
+                // println!("disable IRQ");
+                self.irq_enabled = false;
+                self.has_pending_irq = false;
+            }
+            (0xe000..=0xffff, true) => {
+                self.irq_enabled = true;
+                // println!("enable IRQ");
+            }
+
+            _ => {}
+        }
+    }
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let prg_flag = self.bank_select & 0x40 != 0;
+        let prg_size = prg_rom.len() / 0x2000;
+
+        let mapped_address: usize = match (address, prg_flag) {
+            (0x8000..=0x9fff, false) => address as usize - 0x8000 + self.r[6] as usize * 0x2000,
+            (0x8000..=0x9fff, true) => address as usize - 0x8000 + (prg_size - 2) as usize * 0x2000,
+            (0xa000..=0xbfff, _) => address as usize - 0xa000 + self.r[7] as usize * 0x2000,
+            (0xc000..=0xdfff, false) => {
+                address as usize - 0xc000 + (prg_size - 2) as usize * 0x2000
+            }
+            (0xc000..=0xdfff, true) => address as usize - 0xc000 + self.r[6] as usize * 0x2000,
+            (0xe000..=0xffff, _) => address as usize - 0xe000 + (prg_size - 1) as usize * 0x2000,
+            _ => panic!("Unhandled address: {:#06X}", address),
+        };
+
+        prg_rom[mapped_address as usize]
+    }
+
+    fn read_prg_ram(&mut self, prg_ram: &PrgRam, address: u16) -> Option<u8> {
+        if self.prg_ram_enabled {
+            prg_ram.read(address)
+        } else {
+            None
+        }
+    }
+
+    fn write_prg_ram(&mut self, prg_ram: &mut PrgRam, address: u16, value: u8) {
+        if self.prg_ram_enabled && !self.prg_ram_write_protected {
+            prg_ram.write(address, value)
+        }
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let chr_flag = self.bank_select & 0x80 != 0;
+        let chr_bank_size = 0x400;
+
+        let address = address as usize;
+        match (address, chr_flag) {
+            (0x0000..=0x07ff, false) => address + self.r[0] as usize * chr_bank_size,
+            (0x0800..=0x0fff, false) => address - 0x0800 + self.r[1] as usize * chr_bank_size,
+            (0x1000..=0x13ff, false) => address - 0x1000 + self.r[2] as usize * chr_bank_size,
+            (0x1400..=0x17ff, false) => address - 0x1400 + self.r[3] as usize * chr_bank_size,
+            (0x1800..=0x1bff, false) => address - 0x1800 + self.r[4] as usize * chr_bank_size,
+            (0x1c00..=0x1fff, false) => address - 0x1c00 + self.r[5] as usize * chr_bank_size,
+
+            (0x0000..=0x03ff, true) => address + self.r[2] as usize * chr_bank_size,
+            (0x0400..=0x07ff, true) => address - 0x0400 + self.r[3] as usize * chr_bank_size,
+            (0x0800..=0x0bff, true) => address - 0x0800 + self.r[4] as usize * chr_bank_size,
+            (0x0c00..=0x0fff, true) => address - 0x0c00 + self.r[5] as usize * chr_bank_size,
+            (0x1000..=0x17ff, true) => address - 0x1000 + self.r[0] as usize * chr_bank_size,
+            (0x1800..=0x1fff, true) => address - 0x1800 + self.r[1] as usize * chr_bank_size,
+            _ => panic!("Unhandled address: {:#06X}", address),
+        }
+    }
+
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        Some(self.mirroring.layout())
+    }
+
+    // Revision A only raises the IRQ when the counter gets to zero, not while it stays there.
+    fn a12_rising_edge(&mut self) {
+        let was_counting = self.current_irq_counter > 0 || self.irq_reset;
+
+        if self.current_irq_counter == 0 || self.irq_reset {
+            self.current_irq_counter = self.irq_reload_value;
+            self.irq_reset = false;
+        } else {
+            self.current_irq_counter -= 1;
+        }
+
+        if !self.revision_a || was_counting {
+            self.toggle_pending_irq_if_possible();
+        }
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        self.has_pending_irq
+    }
+}
+
+impl Snapshot for TxROM {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank_select);
+        writer.write_bytes(&self.r);
+        writer.write_bool(self.mirroring == Mirroring::Horizontal);
+        writer.write_u8(self.irq_reload_value);
+        writer.write_bool(self.irq_reset);
+        writer.write_bool(self.irq_enabled);
+        writer.write_u8(self.current_irq_counter);
+        writer.write_bool(self.has_pending_irq);
+        writer.write_bool(self.prg_ram_enabled);
+        writer.write_bool(self.prg_ram_write_protected);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.bank_select = reader.read_u8()?;
+        reader.read_bytes(&mut self.r)?;
+        self.mirroring = if reader.read_bool()? {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+        self.irq_reload_value = reader.read_u8()?;
+        self.irq_reset = reader.read_bool()?;
+        self.irq_enabled = reader.read_bool()?;
+        self.current_irq_counter = reader.read_u8()?;
+        self.has_pending_irq = reader.read_bool()?;
+        self.prg_ram_enabled = reader.read_bool()?;
+        self.prg_ram_write_protected = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// The IRQ counter shared by Konami's VRC chips. It counts up from a latched value to
+/// $FF, either every CPU cycle or once per scanline using a prescaler of CPU cycles.
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enabled_after_acknowledge: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enabled_after_acknowledge: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.enabled_after_acknowledge = value & 1 != 0;
+        self.enabled = value & 2 != 0;
+        self.cycle_mode = value & 4 != 0;
+        self.pending = false;
+
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enabled_after_acknowledge;
+    }
+
+    // A scanline is 113 2/3 CPU cycles, so the prescaler counts in thirds.
+    fn cpu_tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            self.clock();
+            return;
+        }
+
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += 341;
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+impl Snapshot for VrcIrq {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.latch);
+        writer.write_u8(self.counter);
+        writer.write_u16(self.prescaler as u16);
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.enabled_after_acknowledge);
+        writer.write_bool(self.cycle_mode);
+        writer.write_bool(self.pending);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.latch = reader.read_u8()?;
+        self.counter = reader.read_u8()?;
+        self.prescaler = reader.read_u16()? as i16;
+        self.enabled = reader.read_bool()?;
+        self.enabled_after_acknowledge = reader.read_bool()?;
+        self.cycle_mode = reader.read_bool()?;
+        self.pending = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+/// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25). The boards wire different CPU
+/// address lines to the chip's two register select pins, so each variant is described
+/// by the lines feeding them. Without a submapper, the wirings sharing a mapper number
+/// are ORed together, which works since games only ever use one of them.
+struct VRC4 {
+    a0_lines: u16,
+    a1_lines: u16,
+    is_vrc2: bool,
+    // VRC2a ignores the lowest bit of its CHR bank numbers.
+    chr_bank_shift: u8,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    mirroring: u8,
+    chr_banks: [u16; 8],
+    irq: VrcIrq,
+}
+
+impl VRC4 {
+    fn new(mapper: u16, submapper: u8) -> VRC4 {
+        let (a0_lines, a1_lines) = match (mapper, submapper) {
+            (21, 1) => (0x02, 0x04),
+            (21, 2) => (0x40, 0x80),
+            (21, _) => (0x42, 0x84),
+            (22, _) => (0x02, 0x01),
+            (23, 1) | (23, 3) => (0x01, 0x02),
+            (23, 2) => (0x04, 0x08),
+            (23, _) => (0x05, 0x0a),
+            (25, 1) | (25, 3) => (0x02, 0x01),
+            (25, 2) => (0x08, 0x04),
+            _ => (0x0a, 0x05),
+        };
+
+        VRC4 {
+            a0_lines,
+            a1_lines,
+            is_vrc2: mapper == 22 || submapper == 3,
+            chr_bank_shift: if mapper == 22 { 1 } else { 0 },
+            prg_banks: [0; 2],
+            prg_swap: false,
+            mirroring: 0,
+            chr_banks: [0; 8],
+            irq: VrcIrq::new(),
+        }
+    }
+
+    // Turns a CPU address into $x000-$x003 as the chip sees it.
+    fn register(&self, address: u16) -> u16 {
+        let a0 = (address & self.a0_lines != 0) as u16;
+        let a1 = (address & self.a1_lines != 0) as u16;
+
+        address & 0xf000 | a1 << 1 | a0
+    }
+}
+
+impl Mapper for VRC4 {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        let register = self.register(address);
+
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = value & 0x1f,
+            0x9000..=0x9003 if self.is_vrc2 => self.mirroring = value & 1,
+            0x9000..=0x9001 => self.mirroring = value & 3,
+            0x9002..=0x9003 => self.prg_swap = value & 2 != 0,
+            0xa000..=0xa003 => self.prg_banks[1] = value & 0x1f,
+            0xb000..=0xefff => {
+                // Each 1 KiB bank number is written a nibble at a time, low nibble first.
+                let index = ((register >> 12) as usize - 0xb) * 2 + (register as usize & 2) / 2;
+                let bank = &mut self.chr_banks[index];
+
+                if register & 1 == 0 {
+                    *bank = (*bank & 0x1f0) | (value & 0xf) as u16;
+                } else {
+                    *bank = (*bank & 0xf) | ((value & 0x1f) as u16) << 4;
+                }
+            }
+            _ if self.is_vrc2 => {}
+            0xf000 => self.irq.latch = (self.irq.latch & 0xf0) | (value & 0xf),
+            0xf001 => self.irq.latch = (self.irq.latch & 0xf) | value << 4,
+            0xf002 => self.irq.write_control(value),
+            0xf003 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let last_bank = prg_rom.len() / 0x2000 - 1;
+
+        let bank = match (address, self.prg_swap) {
+            (0x8000..=0x9fff, false) | (0xc000..=0xdfff, true) => self.prg_banks[0] as usize,
+            (0xa000..=0xbfff, _) => self.prg_banks[1] as usize,
+            (0x8000..=0x9fff, true) | (0xc000..=0xdfff, false) => last_bank - 1,
+            _ => last_bank,
+        };
+
+        prg_rom[(bank * 0x2000 + (address as usize & 0x1fff)) % prg_rom.len()]
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let bank = self.chr_banks[address as usize / 0x400] >> self.chr_bank_shift;
+
+        bank as usize * 0x400 + (address as usize & 0x3ff)
+    }
+
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        let mirroring = match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::OneScreenLow,
+            _ => Mirroring::OneScreenHigh,
+        };
+
+        Some(mirroring.layout())
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn cpu_tick(&mut self) {
+        self.irq.cpu_tick();
+    }
+}
+
+impl Snapshot for VRC4 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.prg_banks);
+        writer.write_bool(self.prg_swap);
+        writer.write_u8(self.mirroring);
+        for bank in &self.chr_banks {
+            writer.write_u16(*bank);
+        }
+        self.irq.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.prg_banks)?;
+        self.prg_swap = reader.read_bool()?;
+        self.mirroring = reader.read_u8()?;
+        for bank in &mut self.chr_banks {
+            *bank = reader.read_u16()?;
+        }
+        self.irq.load_state(reader)
+    }
+}
+
+/// Konami VRC6 (mappers 24 and 26, the latter with A0 and A1 swapped), with its
+/// three extra sound channels.
+struct VRC6 {
+    swap_address_lines: bool,
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    // $B003: CHR banking mode, mirroring, nametables from CHR ROM and PRG-RAM enable.
+    ppu_banking: u8,
+    chr_banks: [u8; 8],
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl VRC6 {
+    fn new(swap_address_lines: bool) -> VRC6 {
+        VRC6 {
+            swap_address_lines,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            ppu_banking: 0,
+            chr_banks: [0; 8],
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn register(&self, address: u16) -> u16 {
+        let lines = address & 3;
+        let lines = if self.swap_address_lines {
+            (lines & 1) << 1 | lines >> 1
+        } else {
+            lines
+        };
+
+        address & 0xf000 | lines
+    }
+
+    fn chr_bank(&self, slot: usize) -> usize {
+        // With bit 5 set, 2 KiB banks take A10 from the slot rather than the register.
+        let (mask, or) = if self.ppu_banking & 0x20 != 0 {
+            (0xfe, 1)
+        } else {
+            (0xff, 0)
+        };
+        let two_kib_bank = |register: usize| {
+            let bank = self.chr_banks[register];
+            if slot & 1 == 0 {
+                bank & mask
+            } else {
+                bank | or
+            }
+        };
+
+        let bank = match self.ppu_banking & 3 {
+            0 => self.chr_banks[slot],
+            1 => two_kib_bank(slot / 2),
+            _ if slot < 4 => self.chr_banks[slot],
+            _ => two_kib_bank(2 + slot / 2),
+        };
+
+        bank as usize
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        self.ppu_banking & 0x80 != 0
+    }
+}
+
+impl Mapper for VRC6 {
+    fn write_address(&mut self, _prg_rom: &[u8], address: u16, value: u8) {
+        let register = self.register(address);
+
+        match register {
+            0x8000..=0x8003 => self.prg_bank_16k = value & 0xf,
+            0xb003 => self.ppu_banking = value,
+            0x9000..=0xb002 => self.audio.write_register(register, value),
+            0xc000..=0xc003 => self.prg_bank_8k = value & 0x1f,
+            0xd000..=0xe003 => {
+                self.chr_banks[((register >> 12) as usize - 0xd) * 4 + (register as usize & 3)] =
+                    value
+            }
+            0xf000 => self.irq.latch = value,
+            0xf001 => self.irq.write_control(value),
+            0xf002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn read_address(&mut self, prg_rom: &[u8], address: u16) -> u8 {
+        let mapped_address = match address {
+            0x8000..=0xbfff => self.prg_bank_16k as usize * 0x4000 + (address as usize & 0x3fff),
+            0xc000..=0xdfff => self.prg_bank_8k as usize * 0x2000 + (address as usize & 0x1fff),
+            _ => prg_rom.len() - 0x2000 + (address as usize & 0x1fff),
+        };
+
+        prg_rom[mapped_address % prg_rom.len()]
+    }
+
+    fn read_prg_ram(&mut self, prg_ram: &PrgRam, address: u16) -> Option<u8> {
+        if self.is_prg_ram_enabled() {
+            prg_ram.read(address)
+        } else {
+            None
+        }
+    }
+
+    fn write_prg_ram(&mut self, prg_ram: &mut PrgRam, address: u16, value: u8) {
+        if self.is_prg_ram_enabled() {
+            prg_ram.write(address, value)
+        }
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        self.chr_bank(address as usize / 0x400) * 0x400 + (address as usize & 0x3ff)
+    }
+
+    // With bit 4 set the nametables come from CHR ROM. The real chip picks those banks
+    // differently in every banking mode, here R6 and R7 simply take the CIRAM pages' place.
+    fn nametable_layout(&self) -> Option<NametableLayout> {
+        let mirroring = match (self.ppu_banking >> 2) & 3 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::OneScreenLow,
+            _ => Mirroring::OneScreenHigh,
+        };
+        let mut layout = mirroring.layout();
+
+        if self.ppu_banking & 0x10 != 0 {
+            for source in layout.iter_mut() {
+                if let NametableSource::Ciram(page) = *source {
+                    *source = NametableSource::Chr(self.chr_banks[6 + page as usize] as u16);
+                }
+            }
+        }
+
+        Some(layout)
+    }
+
+    fn has_pending_irq(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn cpu_tick(&mut self) {
+        self.irq.cpu_tick();
+    }
+
+    fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        Some(&mut self.audio)
+    }
+}
+
+impl Snapshot for VRC6 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.prg_bank_16k);
+        writer.write_u8(self.prg_bank_8k);
+        writer.write_u8(self.ppu_banking);
+        writer.write_bytes(&self.chr_banks);
+        self.irq.save_state(writer);
+        self.audio.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_bank_16k = reader.read_u8()?;
+        self.prg_bank_8k = reader.read_u8()?;
+        self.ppu_banking = reader.read_u8()?;
+        reader.read_bytes(&mut self.chr_banks)?;
+        self.irq.load_state(reader)?;
+        self.audio.load_state(reader)
+    }
+}
+
+// PPU dots A12 must stay low before MMC3 counts a rise, about three CPU cycles.
+const A12_MIN_LOW_DOTS: u64 = 10;
+
+/// Finds rises of PPU A12 the way MMC3 does. A rise only counts when A12 was low for a
+/// while, so the brief lows between fetches from the right pattern table are ignored.
+struct A12Filter {
+    high: bool,
+    low_since: u64,
+}
+
+impl A12Filter {
+    fn new() -> A12Filter {
+        A12Filter {
+            high: false,
+            low_since: 0,
+        }
+    }
+
+    fn is_rising_edge(&mut self, address: u16, cycle: u64) -> bool {
+        let high = address & 0x1000 != 0;
+        let was_high = std::mem::replace(&mut self.high, high);
+
+        if !high && was_high {
+            self.low_since = cycle;
+        }
+
+        high && !was_high && cycle.wrapping_sub(self.low_since) >= A12_MIN_LOW_DOTS
+    }
+}
+
+impl Snapshot for A12Filter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.high);
+        writer.write_u64(self.low_since);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.high = reader.read_bool()?;
+        self.low_since = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+// The board's extra 2 KiB of VRAM backs the bottom two nametables.
+const FOUR_SCREEN_LAYOUT: NametableLayout = [
+    NametableSource::Ciram(0),
+    NametableSource::Ciram(1),
+    NametableSource::CartridgeRam(0),
+    NametableSource::CartridgeRam(1),
+];
+
 pub struct Cartridge {
     prg_rom: Vec<u8>,
     chr_rom: Vec<u8>,
-    mirroring: Mirroring,
+    chr_ram: Vec<u8>,
+    prg_ram: PrgRam,
+    nametable_ram: Vec<u8>,
+    a12_filter: A12Filter,
+    header: RomHeader,
     mapper: Box<dyn Mapper>,
 }
 
@@ -451,76 +1736,724 @@ impl Cartridge {
         self.mapper.read_address(&self.prg_rom, address)
     }
 
-    pub fn read_chr_rom(&self, address: u16) -> Option<u8> {
-        self.mapper.read_chr_rom(&self.chr_rom, address)
+    pub fn read_prg_ram(&mut self, address: u16) -> Option<u8> {
+        self.mapper.read_prg_ram(&self.prg_ram, address)
+    }
+
+    pub fn write_prg_ram(&mut self, address: u16, value: u8) {
+        self.mapper.write_prg_ram(&mut self.prg_ram, address, value)
+    }
+
+    pub fn prg_ram(&self) -> &PrgRam {
+        &self.prg_ram
+    }
+
+    pub fn prg_ram_mut(&mut self) -> &mut PrgRam {
+        &mut self.prg_ram
+    }
+
+    /// Whether the PRG-RAM is battery backed and should be persisted between sessions.
+    pub fn has_battery(&self) -> bool {
+        self.header.has_battery
+    }
+
+    /// Reads the pattern tables, from CHR-RAM when the cartridge has no CHR ROM.
+    pub fn read_chr(&self, address: u16) -> u8 {
+        if self.chr_rom.is_empty() {
+            self.mapper.read_chr(&self.chr_ram, address)
+        } else {
+            self.mapper.read_chr(&self.chr_rom, address)
+        }
+    }
+
+    /// Like `read_chr`, but lets the mapper observe the fetch. Used for the
+    /// PPU's own accesses, as opposed to debugging views of the pattern tables.
+    pub fn fetch_chr(&mut self, address: u16) -> u8 {
+        let value = self.read_chr(address);
+        self.mapper.chr_fetched(address);
+        value
+    }
+
+    pub fn read_expansion(&mut self, address: u16) -> Option<u8> {
+        self.mapper.read_expansion(address)
+    }
+
+    pub fn write_expansion(&mut self, address: u16, value: u8) {
+        self.mapper.write_expansion(address, value)
+    }
+
+    pub fn ppu_register_written(&mut self, address: u16, value: u8) {
+        self.mapper.ppu_register_written(address, value)
+    }
+
+    pub fn scanline_started(&mut self, scanline: u32, rendering: bool) {
+        self.mapper.scanline_started(scanline, rendering)
+    }
+
+    /// Reads a nametable byte at $2000-$2FFF from wherever the layout puts its slot.
+    /// `ciram` is the console's own 2 KiB of nametable RAM.
+    pub fn read_nametable(&self, address: u16, ciram: &[u8]) -> u8 {
+        let offset = address as usize & 0x3ff;
+
+        match self.nametable_source(address) {
+            NametableSource::Ciram(page) => ciram[(page as usize & 1) * 0x400 + offset],
+            NametableSource::CartridgeRam(page) => *self
+                .nametable_ram
+                .get(page as usize * 0x400 + offset)
+                .unwrap_or(&0),
+            NametableSource::Chr(bank) => {
+                let chr = if self.chr_rom.is_empty() {
+                    &self.chr_ram
+                } else {
+                    &self.chr_rom
+                };
+                chr[(bank as usize * 0x400 + offset) % chr.len()]
+            }
+            NametableSource::Mapper => self.mapper.read_nametable(address),
+        }
+    }
+
+    /// Like `read_nametable`, but lets the mapper observe and replace the fetch.
+    pub fn fetch_nametable(&mut self, address: u16, ciram: &[u8]) -> u8 {
+        let value = self.read_nametable(address, ciram);
+        self.mapper.nametable_fetched(address, value)
+    }
+
+    /// Writes to nametables in CHR ROM are ignored.
+    pub fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) {
+        let offset = address as usize & 0x3ff;
+
+        match self.nametable_source(address) {
+            NametableSource::Ciram(page) => ciram[(page as usize & 1) * 0x400 + offset] = value,
+            NametableSource::CartridgeRam(page) => {
+                if let Some(byte) = self.nametable_ram.get_mut(page as usize * 0x400 + offset) {
+                    *byte = value;
+                }
+            }
+            NametableSource::Chr(bank) => {
+                if self.chr_rom.is_empty() {
+                    let index = (bank as usize * 0x400 + offset) % self.chr_ram.len();
+                    self.chr_ram[index] = value;
+                }
+            }
+            NametableSource::Mapper => self.mapper.write_nametable(address, value),
+        }
+    }
+
+    /// Boards with four-screen VRAM hardwire it, whatever the mapper selects.
+    fn nametable_source(&self, address: u16) -> NametableSource {
+        let layout = if self.header.four_screen {
+            FOUR_SCREEN_LAYOUT
+        } else {
+            self.mapper
+                .nametable_layout()
+                .unwrap_or_else(|| self.header.mirroring.layout())
+        };
+
+        layout[(address as usize >> 10) & 3]
+    }
+
+    pub fn cpu_tick(&mut self) {
+        self.mapper.cpu_tick()
     }
 
-    pub fn mirroring(&self) -> Mirroring {
-        self.mapper.mirroring().unwrap_or(self.mirroring)
+    pub fn expansion_audio(&mut self) -> Option<&mut dyn ExpansionAudio> {
+        self.mapper.expansion_audio()
     }
 
-    pub fn scanline_tick(&mut self) {
-        self.mapper.scanline_tick()
+    /// Writes to CHR-RAM. Writes to CHR ROM are ignored.
+    pub fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_rom.is_empty() {
+            self.mapper.write_chr(&mut self.chr_ram, address, value)
+        }
+    }
+
+    /// Called with every address the PPU puts on its bus for a pattern fetch or a
+    /// $2006/$2007 access, along with the PPU cycle it happened on.
+    pub fn ppu_bus_address(&mut self, address: u16, cycle: u64) {
+        if self.a12_filter.is_rising_edge(address, cycle) {
+            self.mapper.a12_rising_edge();
+        }
     }
 
     pub fn has_pending_irq(&self) -> bool {
         self.mapper.has_pending_irq()
     }
+
+    pub fn rom_checksum(&self) -> u32 {
+        rom_checksum(&[self.prg_rom.as_slice(), self.chr_rom.as_slice()].concat())
+    }
 }
 
-pub fn load_cartridge<S: Into<String>>(source: S) -> Result<Cartridge, RomParseError> {
-    let bytes: Vec<u8> = std::fs::read(source.into()).unwrap().into_iter().collect();
+impl Snapshot for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.prg_ram.save_state(writer);
+        writer.write_bytes(&self.chr_ram);
+        writer.write_bytes(&self.nametable_ram);
+        self.a12_filter.save_state(writer);
+        self.mapper.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.prg_ram.load_state(reader)?;
+        reader.read_bytes(&mut self.chr_ram)?;
+        reader.read_bytes(&mut self.nametable_ram)?;
+        self.a12_filter.load_state(reader)?;
+        self.mapper.load_state(reader)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeaderFormat {
+    INes,
+    Nes2,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimingRegion {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+/// The decoded 16 byte header of an iNES 1.0 or NES 2.0 file. All sizes are in bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub four_screen: bool,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub timing: TimingRegion,
+}
+
+const HEADER_SIZE: usize = 0x10;
+const TRAINER_SIZE: usize = 0x200;
+
+// NES 2.0 stores RAM sizes as a shift count: 0 means none, otherwise 64 << n bytes.
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+// NES 2.0 ROM sizes are either a plain count of `unit` sized banks, or when the
+// high nibble is $F, an exponent-multiplier pair: 2^E * (MM * 2 + 1). `None` if the
+// size doesn't fit in a `usize`.
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0xf {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+    } else {
+        ((msb as usize) << 8 | lsb as usize).checked_mul(unit)
+    }
+}
+
+impl RomHeader {
+    pub fn parse(bytes: &[u8]) -> Result<RomHeader, RomParseError> {
+        if bytes.get(0..4) != Some(&[0x4e, 0x45, 0x53, 0x1a][..]) {
+            return Err(RomParseError::NotInes);
+        }
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(RomParseError::TruncatedHeader);
+        }
+
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+
+        let mirroring = if flags6 & 1 == 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        };
+        let has_battery = flags6 & 0b10 != 0;
+        let has_trainer = flags6 & 0b100 != 0;
+        let four_screen = flags6 & 0b1000 != 0;
+
+        if flags7 & 0b1100 == 0b1000 {
+            let mapper =
+                (flags6 >> 4) as u16 | (flags7 & 0xf0) as u16 | ((bytes[8] & 0xf) as u16) << 8;
+
+            return Ok(RomHeader {
+                format: HeaderFormat::Nes2,
+                mapper,
+                submapper: bytes[8] >> 4,
+                prg_rom_size: nes2_rom_size(bytes[4], bytes[9] & 0xf, 0x4000)
+                    .ok_or(RomParseError::RomSizeTooLarge)?,
+                chr_rom_size: nes2_rom_size(bytes[5], bytes[9] >> 4, 0x2000)
+                    .ok_or(RomParseError::RomSizeTooLarge)?,
+                prg_ram_size: nes2_ram_size(bytes[10] & 0xf),
+                prg_nvram_size: nes2_ram_size(bytes[10] >> 4),
+                chr_ram_size: nes2_ram_size(bytes[11] & 0xf),
+                chr_nvram_size: nes2_ram_size(bytes[11] >> 4),
+                mirroring,
+                four_screen,
+                has_battery,
+                has_trainer,
+                timing: match bytes[12] & 0b11 {
+                    0 => TimingRegion::Ntsc,
+                    1 => TimingRegion::Pal,
+                    2 => TimingRegion::MultiRegion,
+                    _ => TimingRegion::Dendy,
+                },
+            });
+        }
+
+        // Old dumping tools wrote their name ("DiskDude!") over bytes 7-15,
+        // which makes byte 7's mapper bits meaningless.
+        if flags7 & 0b1100 != 0 || bytes[12..16].iter().any(|byte| *byte != 0) {
+            return Err(RomParseError::DirtyHeader);
+        }
 
-    if bytes[0..4] != [0x4e, 0x45, 0x53, 0x1a] {
-        return Err(RomParseError::NotInes);
+        let prg_rom_size = bytes[4] as usize * 0x4000;
+        let chr_rom_size = bytes[5] as usize * 0x2000;
+
+        // iNES 1.0 can't describe RAM reliably, so assume the common 8 KiB of
+        // PRG-RAM and 8 KiB of CHR-RAM for boards without CHR-ROM.
+        let prg_ram_size = bytes[8].max(1) as usize * 0x2000;
+
+        Ok(RomHeader {
+            format: HeaderFormat::INes,
+            mapper: (flags6 >> 4) as u16 | (flags7 & 0xf0) as u16,
+            submapper: 0,
+            prg_rom_size,
+            chr_rom_size,
+            prg_ram_size: if has_battery { 0 } else { prg_ram_size },
+            prg_nvram_size: if has_battery { prg_ram_size } else { 0 },
+            chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
+            mirroring,
+            four_screen,
+            has_battery,
+            has_trainer,
+            timing: if bytes[9] & 1 == 0 {
+                TimingRegion::Ntsc
+            } else {
+                TimingRegion::Pal
+            },
+        })
     }
+}
+
+pub fn load_cartridge<S: Into<String>>(source: S) -> Result<Cartridge, RomParseError> {
+    let bytes: Vec<u8> = std::fs::read(source.into())?;
+    parse_cartridge(&bytes)
+}
 
-    let prg_rom_size = bytes[4] as usize * 0x4000;
-    let chr_rom_size = bytes[5] as usize * 0x2000;
+/// Builds a cartridge from the contents of an iNES or NES 2.0 file.
+pub fn parse_cartridge(bytes: &[u8]) -> Result<Cartridge, RomParseError> {
+    let header = RomHeader::parse(bytes)?;
 
-    let prg_and_chr_data = &bytes[0x10..];
+    let mut prg_and_chr_data = &bytes[HEADER_SIZE..];
+    if header.has_trainer {
+        if prg_and_chr_data.len() < TRAINER_SIZE {
+            return Err(RomParseError::PrgRomTooSmall);
+        }
+
+        prg_and_chr_data = &prg_and_chr_data[TRAINER_SIZE..];
+    }
 
-    if prg_rom_size > prg_and_chr_data.len() {
+    if header.prg_rom_size > prg_and_chr_data.len() {
         return Err(RomParseError::PrgRomTooSmall);
     }
 
-    let prg_rom: Vec<u8> = Vec::from(&prg_and_chr_data[0..prg_rom_size]);
+    let prg_rom: Vec<u8> = Vec::from(&prg_and_chr_data[0..header.prg_rom_size]);
 
-    let chr_data = &prg_and_chr_data[prg_rom_size..];
+    let chr_data = &prg_and_chr_data[header.prg_rom_size..];
 
-    if chr_rom_size > chr_data.len() {
+    if header.chr_rom_size > chr_data.len() {
         return Err(RomParseError::ChrRomTooSmall);
     }
 
-    let chr_rom: Vec<u8> = Vec::from(chr_data);
+    let chr_rom: Vec<u8> = Vec::from(&chr_data[0..header.chr_rom_size]);
 
-    let mirroring = if bytes[6] & 1 == 0 {
-        Mirroring::Horizontal
-    } else {
-        Mirroring::Vertical
+    // A cartridge with neither CHR ROM nor RAM declared still needs pattern tables.
+    let chr_ram_size = match header.chr_ram_size + header.chr_nvram_size {
+        0 if chr_rom.is_empty() => 0x2000,
+        size => size,
     };
 
-    let mapper_number = bytes[6] >> 4;
-    let mapper: Box<dyn Mapper> = match bytes[6] >> 4 {
+    let mapper: Box<dyn Mapper> = match header.mapper {
         0 => Box::new(NROM {}),
         1 => Box::new(SNROM::new()),
         2 => Box::new(UNROM::new()),
         3 => Box::new(CNROM::new()),
-        4 => Box::new(TxROM::new()),
-        _ => return Err(RomParseError::UnsupportedMapper(mapper_number)),
+        // Submapper 4 is the old MMC3A, every other board behaves like MMC3B/C.
+        4 => Box::new(TxROM::new(header.submapper == 4)),
+        5 => Box::new(ExROM::new()),
+        7 => Box::new(AxROM::new()),
+        9 => Box::new(PxROM::new()),
+        10 => Box::new(FxROM::new()),
+        21 | 22 | 23 | 25 => Box::new(VRC4::new(header.mapper, header.submapper)),
+        24 => Box::new(VRC6::new(false)),
+        26 => Box::new(VRC6::new(true)),
+        _ => return Err(RomParseError::UnsupportedMapper(header.mapper)),
     };
 
     Ok(Cartridge {
         prg_rom,
         chr_rom,
-        mirroring,
+        chr_ram: vec![0; chr_ram_size],
+        prg_ram: PrgRam::new(header.prg_ram_size + header.prg_nvram_size),
+        nametable_ram: vec![0; if header.four_screen { 0x800 } else { 0 }],
+        a12_filter: A12Filter::new(),
+        header,
         mapper,
     })
 }
 
+#[derive(Debug)]
 pub enum RomParseError {
+    Io(std::io::Error),
     NotInes,
+    TruncatedHeader,
+    DirtyHeader,
+    RomSizeTooLarge,
     PrgRomTooSmall,
     ChrRomTooSmall,
-    UnsupportedMapper(u8),
+    UnsupportedMapper(u16),
+}
+
+impl std::fmt::Display for RomParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomParseError::Io(error) => write!(f, "{}", error),
+            RomParseError::NotInes => write!(f, "not an iNES file"),
+            RomParseError::TruncatedHeader => write!(f, "the iNES header is truncated"),
+            RomParseError::DirtyHeader => {
+                write!(f, "the iNES header contains garbage in bytes 7-15")
+            }
+            RomParseError::RomSizeTooLarge => write!(f, "the header's ROM size is too large"),
+            RomParseError::PrgRomTooSmall => write!(f, "the file is smaller than its PRG ROM size"),
+            RomParseError::ChrRomTooSmall => write!(f, "the file is smaller than its CHR ROM size"),
+            RomParseError::UnsupportedMapper(mapper) => {
+                write!(f, "mapper {} is not supported", mapper)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RomParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RomParseError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RomParseError {
+    fn from(error: std::io::Error) -> RomParseError {
+        RomParseError::Io(error)
+    }
+}
+
+/// An NROM cartridge with the given ROM contents, for tests that need a bus or a PPU.
+#[cfg(test)]
+pub fn nrom_cartridge(prg_rom: &[u8], chr_rom: &[u8]) -> Cartridge {
+    let mut bytes = vec![
+        0x4e,
+        0x45,
+        0x53,
+        0x1a,
+        (prg_rom.len() / 0x4000) as u8,
+        (chr_rom.len() / 0x2000) as u8,
+    ];
+    bytes.resize(HEADER_SIZE, 0);
+    bytes.extend_from_slice(prg_rom);
+    bytes.extend_from_slice(chr_rom);
+
+    parse_cartridge(&bytes).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(flags: [u8; 12]) -> Vec<u8> {
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a];
+        bytes.extend_from_slice(&flags);
+        bytes
+    }
+
+    #[test]
+    fn parse_ines_header() {
+        let bytes = header_bytes([2, 1, 0b01000011, 0x10, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&bytes).unwrap();
+
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper, 0x14);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.has_battery);
+        assert!(!header.has_trainer);
+        assert_eq!(header.timing, TimingRegion::Ntsc);
+    }
+
+    #[test]
+    fn parse_nes2_header() {
+        let bytes = header_bytes([
+            0x20, 0, 0b00011100, 0b00001000, 0x31, 0x00, 0x70, 0x07, 0x01, 0, 0, 0,
+        ]);
+        let header = RomHeader::parse(&bytes).unwrap();
+
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.mapper, 0x101);
+        assert_eq!(header.submapper, 3);
+        assert_eq!(header.prg_rom_size, 0x80000);
+        assert_eq!(header.chr_rom_size, 0);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert!(header.four_screen);
+        assert!(header.has_trainer);
+        assert_eq!(header.timing, TimingRegion::Pal);
+    }
+
+    #[test]
+    fn parse_nes2_exponent_rom_size() {
+        let bytes = header_bytes([0b00010101, 0, 0, 0b00001000, 0, 0x0f, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&bytes).unwrap();
+
+        assert_eq!(header.prg_rom_size, (1 << 5) * 3);
+    }
+
+    #[test]
+    fn reject_nes2_rom_size_overflow() {
+        let bytes = header_bytes([0xfd, 0, 0, 0b00001000, 0, 0x0f, 0, 0, 0, 0, 0, 0]);
+
+        assert!(matches!(
+            RomHeader::parse(&bytes),
+            Err(RomParseError::RomSizeTooLarge)
+        ));
+    }
+
+    #[test]
+    fn reject_truncated_header() {
+        assert!(matches!(
+            RomHeader::parse(b"NES"),
+            Err(RomParseError::NotInes)
+        ));
+        assert!(matches!(
+            RomHeader::parse(&[0x4e, 0x45, 0x53, 0x1a, 2, 1]),
+            Err(RomParseError::TruncatedHeader)
+        ));
+    }
+
+    #[test]
+    fn reject_dirty_header() {
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a, 2, 1, 0x10];
+        bytes.extend_from_slice(b"DiskDude!");
+
+        assert!(matches!(
+            RomHeader::parse(&bytes),
+            Err(RomParseError::DirtyHeader)
+        ));
+    }
+
+    #[test]
+    fn txrom_prg_ram_protection() {
+        let mut mapper = TxROM::new(false);
+        let mut prg_ram = PrgRam::new(0x2000);
+
+        mapper.write_prg_ram(&mut prg_ram, 0x6000, 0x12);
+        assert_eq!(mapper.read_prg_ram(&prg_ram, 0x6000), Some(0x12));
+
+        mapper.write_address(&[], 0xa001, 0xc0);
+        mapper.write_prg_ram(&mut prg_ram, 0x6000, 0x34);
+        assert_eq!(mapper.read_prg_ram(&prg_ram, 0x6000), Some(0x12));
+
+        mapper.write_address(&[], 0xa001, 0x00);
+        assert_eq!(mapper.read_prg_ram(&prg_ram, 0x6000), None);
+
+        mapper.write_address(&[], 0xa001, 0x80);
+        mapper.write_prg_ram(&mut prg_ram, 0x6000, 0x34);
+        assert_eq!(mapper.read_prg_ram(&prg_ram, 0x6000), Some(0x34));
+    }
+
+    #[test]
+    fn snrom_switches_chr_ram_banks() {
+        let mut mapper = SNROM::new();
+        let mut chr_ram = vec![0; 0x2000];
+
+        // Serially write 4 KiB CHR mode to control, then bank 1 into $0000.
+        for bit in 0..5 {
+            mapper.write_address(&[], 0x8000, (0b11100 >> bit) & 1);
+        }
+        for bit in 0..5 {
+            mapper.write_address(&[], 0xa000, (0b00001 >> bit) & 1);
+        }
+
+        mapper.write_chr(&mut chr_ram, 0x0010, 0xab);
+
+        assert_eq!(chr_ram[0x1010], 0xab);
+        assert_eq!(mapper.read_chr(&chr_ram, 0x0010), 0xab);
+        assert_eq!(mapper.read_chr(&chr_ram, 0x1010), 0);
+    }
+
+    #[test]
+    fn mmc2_latch_switches_on_tile_fetch() {
+        let mut mapper = PxROM::new();
+        mapper.write_address(&[], 0xb000, 4);
+        mapper.write_address(&[], 0xc000, 5);
+        mapper.write_address(&[], 0xd000, 6);
+        mapper.write_address(&[], 0xe000, 7);
+
+        assert_eq!(mapper.chr_address(0x0000), 5 * 0x1000);
+        assert_eq!(mapper.chr_address(0x1000), 7 * 0x1000);
+
+        mapper.chr_fetched(0x0fd9);
+        assert_eq!(mapper.chr_address(0x0000), 5 * 0x1000);
+
+        mapper.chr_fetched(0x0fd8);
+        mapper.chr_fetched(0x1fdb);
+        assert_eq!(mapper.chr_address(0x0010), 4 * 0x1000 + 0x10);
+        assert_eq!(mapper.chr_address(0x1010), 6 * 0x1000 + 0x10);
+
+        mapper.chr_fetched(0x1fe8);
+        assert_eq!(mapper.chr_address(0x1010), 7 * 0x1000 + 0x10);
+    }
+
+    #[test]
+    fn vrc_register_wiring_and_irq() {
+        // VRC4b and VRC4d share mapper 25, with A0 and A1 on different CPU lines.
+        let mut mapper = VRC4::new(25, 0);
+        mapper.write_address(&[], 0xb000, 0x5);
+        mapper.write_address(&[], 0xb002, 0x1);
+        mapper.write_address(&[], 0xb004, 0x3);
+        mapper.write_address(&[], 0xb003, 0x2);
+        assert_eq!(mapper.chr_address(0x0010), 0x15 * 0x400 + 0x10);
+        assert_eq!(mapper.chr_address(0x0410), 0x23 * 0x400 + 0x10);
+
+        mapper.write_address(&[], 0xf000, 0xe);
+        mapper.write_address(&[], 0xf002, 0xf);
+        mapper.write_address(&[], 0xf004, 0b110);
+        mapper.cpu_tick();
+        assert!(!mapper.has_pending_irq());
+        mapper.cpu_tick();
+        assert!(mapper.has_pending_irq());
+
+        mapper.write_address(&[], 0xf00c, 0);
+        assert!(!mapper.has_pending_irq());
+
+        // VRC6b swaps A0 and A1, so $D001 is R2.
+        let mut mapper = VRC6::new(true);
+        mapper.write_address(&[], 0xd001, 7);
+        assert_eq!(mapper.chr_address(0x0810), 7 * 0x400 + 0x10);
+    }
+
+    #[test]
+    fn a12_filter_ignores_short_lows() {
+        let mut filter = A12Filter::new();
+
+        assert!(filter.is_rising_edge(0x1000, 20));
+        assert!(!filter.is_rising_edge(0x1010, 22));
+        assert!(!filter.is_rising_edge(0x0000, 24));
+        assert!(!filter.is_rising_edge(0x1000, 28));
+        assert!(!filter.is_rising_edge(0x0ff0, 30));
+        assert!(filter.is_rising_edge(0x1ff0, 40));
+    }
+
+    #[test]
+    fn mmc3_irq_revisions() {
+        // With a reload value of 0, revision B fires on every clock, revision A only once.
+        for (revision_a, expected) in
+            [(false, [true, true, true]), (true, [true, false, false])].iter()
+        {
+            let mut mapper = TxROM::new(*revision_a);
+            mapper.write_address(&[], 0xc000, 0);
+            mapper.write_address(&[], 0xc001, 0);
+            mapper.write_address(&[], 0xe001, 0);
+
+            for pending in expected.iter() {
+                mapper.a12_rising_edge();
+                assert_eq!(mapper.has_pending_irq(), *pending);
+                mapper.write_address(&[], 0xe000, 0);
+                mapper.write_address(&[], 0xe001, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn four_screen_nametables() {
+        let bytes = header_bytes([1, 0, 0b01111000, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&bytes).unwrap();
+
+        let mut cartridge = Cartridge {
+            prg_rom: vec![0; 0x4000],
+            chr_rom: Vec::new(),
+            chr_ram: vec![0; 0x2000],
+            prg_ram: PrgRam::new(0),
+            nametable_ram: vec![0; 0x800],
+            a12_filter: A12Filter::new(),
+            header,
+            mapper: Box::new(AxROM::new()),
+        };
+        let mut ciram = [0u8; 0x800];
+
+        for (slot, address) in [0x2000, 0x2400, 0x2800, 0x2c00].iter().enumerate() {
+            cartridge.write_nametable(*address + 5, slot as u8 + 1, &mut ciram);
+        }
+
+        assert_eq!(ciram[5], 1);
+        assert_eq!(ciram[0x405], 2);
+        assert_eq!(cartridge.nametable_ram[5], 3);
+        assert_eq!(cartridge.read_nametable(0x2c05, &ciram), 4);
+    }
+
+    #[test]
+    fn exrom_registers() {
+        let mut mapper = ExROM::new();
+
+        mapper.write_expansion(0x5205, 12);
+        mapper.write_expansion(0x5206, 34);
+        assert_eq!(mapper.read_expansion(0x5205), Some((12 * 34) as u8));
+        assert_eq!(mapper.read_expansion(0x5206), Some(((12 * 34) >> 8) as u8));
+
+        // Fill mode in the top right nametable, CIRAM elsewhere.
+        mapper.write_expansion(0x5105, 0b01_00_11_00);
+        mapper.write_expansion(0x5106, 0x42);
+        mapper.write_expansion(0x5107, 2);
+        assert_eq!(
+            mapper.nametable_layout(),
+            Some([
+                NametableSource::Ciram(0),
+                NametableSource::Mapper,
+                NametableSource::Ciram(0),
+                NametableSource::Ciram(1),
+            ])
+        );
+        assert_eq!(mapper.read_nametable(0x2405), 0x42);
+        assert_eq!(mapper.read_nametable(0x27c0), 0xaa);
+
+        mapper.write_expansion(0x5203, 3);
+        mapper.write_expansion(0x5204, 0x80);
+        for scanline in 0..3 {
+            mapper.scanline_started(scanline, true);
+        }
+        assert!(!mapper.has_pending_irq());
+
+        mapper.scanline_started(3, true);
+        assert!(mapper.has_pending_irq());
+        assert_eq!(mapper.read_expansion(0x5204), Some(0xc0));
+        assert!(!mapper.has_pending_irq());
+    }
 }
@@ -1,82 +1,80 @@
-use std::{cell::RefCell, collections::HashSet, rc::Rc, u8};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::apu::Apu;
 use crate::ppu::VideoMemoryBuffer;
 use crate::{
-    bus::{JoypadButton, JoypadState, MemoryBuffer, RealBus},
+    bus::{JoypadButton, JoypadPort, MemoryBuffer, RealBus},
     cpu::Cpu,
-    ines::load_cartridge,
-    log_ppu,
+    ines::{load_cartridge, RomParseError},
+    movie::{Movie, MovieError, MovieFrame},
     ppu::Ppu,
+    save_state::{read_header, write_header, SaveStateError, Snapshot, StateReader, StateWriter},
 };
 
 pub enum SideEffect {
     Render,
 }
 
+enum MovieMode {
+    Recording(Movie),
+    Playing(Movie),
+}
+
+struct ActiveMovie {
+    mode: MovieMode,
+    start_frame: u64,
+}
+
 pub struct Machine {
     cpu: Cpu,
-    cycle_counter: ScanlineCycleCounter,
-    pending_cycles: u32,
+    battery_save_path: Option<PathBuf>,
+    frame_count: u64,
+    movie: Option<ActiveMovie>,
 }
 
 impl Machine {
-    fn has_pending_irq(&self) -> bool {
-        self.cpu.bus.apu.has_pending_irq() || self.cpu.bus.cartridge.borrow().has_pending_irq()
-    }
+    pub fn load(file_path: &String, mut apu: Apu) -> Result<Machine, RomParseError> {
+        let mut cartridge = load_cartridge(file_path)?;
+
+        let battery_save_path = if cartridge.has_battery() {
+            let path = Path::new(file_path).with_extension("sav");
+            if path.exists() {
+                cartridge.prg_ram_mut().load(&std::fs::read(&path)?);
+            }
+
+            Some(path)
+        } else {
+            None
+        };
 
-    pub fn load(file_path: &String, mut apu: Apu) -> Result<Machine, std::io::Error> {
-        // todo: fix error type
-        let cartridge = load_cartridge(file_path).ok().unwrap();
         let cartridge = Rc::new(RefCell::new(cartridge));
 
         apu.set_cartridge(cartridge.clone());
 
-        let bus = RealBus {
-            memory: [0; 0x10000],
-            active_buttons: HashSet::new(),
-            joypad_state: JoypadState::Idle,
-            ppu: Ppu::new(cartridge.clone()),
-            cartridge,
-            apu,
-        };
+        let bus = RealBus::new(cartridge, apu);
 
         // println!("chr rom {:?}", &rom.chr_rom_data());
         return Ok(Machine {
             cpu: Cpu::load(bus),
-            cycle_counter: ScanlineCycleCounter::new(),
-            pending_cycles: 0,
+            battery_save_path,
+            frame_count: 0,
+            movie: None,
         });
     }
 
+    /// Runs one CPU instruction, or an interrupt sequence, along with everything
+    /// the PPU and APU do meanwhile.
     pub fn step(&mut self) -> Option<SideEffect> {
-        if self.pending_cycles == 0 {
-            if self.has_pending_irq() {
-                self.cpu.enter_irq();
-            }
-
-            let result = self.cpu.step();
-            let cycles = result.cycles_elapsed;
-            self.pending_cycles = cycles;
-        }
-
-        self.pending_cycles -= 1;
+        self.cpu.step();
 
-        self.cpu.bus.apu.half_step();
-
-        let mut should_render = false;
-
-        for _ in 0..3 {
-            let result = self.cpu.bus.ppu.step();
-
-            should_render = should_render || result;
-        }
-
-        if should_render {
-            if self.cpu.bus.ppu.generates_nmi_at_vblank() {
-                log_ppu!("Enter vblank");
-                self.cpu.enter_nmi();
-            }
+        if self.cpu.bus.take_frame_completed() {
+            self.frame_count += 1;
+            self.advance_movie();
 
             return Some(SideEffect::Render);
         }
@@ -84,6 +82,12 @@ impl Machine {
         None
     }
 
+    /// Turning the limit off removes sprite flicker, at the cost of showing
+    /// sprites some games deliberately hide behind eight others.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.cpu.bus.ppu.set_sprite_limit(enabled);
+    }
+
     pub fn get_buffer(&self) -> &MemoryBuffer {
         &self.cpu.get_memory_buffer()
     }
@@ -100,33 +104,355 @@ impl Machine {
         &self.cpu
     }
 
-    pub fn set_active_buttons(&mut self, buttons: HashSet<JoypadButton>) {
-        self.cpu.bus.active_buttons = buttons;
+    /// Number of frames rendered since power-on, i.e. how many times `step`
+    /// returned `SideEffect::Render`.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
     }
-}
 
-#[derive(PartialEq, Eq)]
-struct ScanlineCycleCounter {
-    scanline_cycles_left: u32,
-}
+    /// Writes battery-backed PRG-RAM to the `.sav` file next to the ROM,
+    /// if the cartridge has a battery and the RAM changed since the last flush.
+    pub fn flush_battery_save(&mut self) -> Result<(), std::io::Error> {
+        let path = match &self.battery_save_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut cartridge = self.cpu.bus.cartridge.borrow_mut();
+        if cartridge.prg_ram().is_dirty() {
+            std::fs::write(path, cartridge.prg_ram().bytes())?;
+            cartridge.prg_ram_mut().clear_dirty();
+        }
+
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        write_header(&mut writer, self.cpu.bus.cartridge.borrow().rom_checksum());
 
-impl ScanlineCycleCounter {
-    fn advance(&mut self, cycles: u32) -> bool {
-        if cycles >= self.scanline_cycles_left {
-            self.scanline_cycles_left = 341 + self.scanline_cycles_left - cycles;
-            return true;
+        writer.write_u64(self.frame_count);
+        self.cpu.save_state(&mut writer);
+
+        writer.into_bytes()
+    }
+
+    /// Restores a state created by `save_state`. If the state turns out to be
+    /// malformed halfway through, the machine is put back the way it was.
+    /// An active movie is moved to the state's frame, or stopped if the state
+    /// is from before the movie started.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(bytes);
+        read_header(&mut reader, self.cpu.bus.cartridge.borrow().rom_checksum())?;
+
+        let backup = self.save_state();
+
+        let result = self.load_state_body(&mut reader).and_then(|_| {
+            if reader.is_at_end() {
+                Ok(())
+            } else {
+                Err(SaveStateError::InvalidValue)
+            }
+        });
+
+        if result.is_err() {
+            let mut reader = StateReader::new(&backup);
+            read_header(&mut reader, self.cpu.bus.cartridge.borrow().rom_checksum())?;
+            self.load_state_body(&mut reader)?;
         } else {
-            self.scanline_cycles_left -= cycles;
-            return false;
+            self.seek_movie();
+        }
+
+        result
+    }
+
+    fn load_state_body(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        self.frame_count = reader.read_u64()?;
+        self.cpu.load_state(reader)
+    }
+
+    pub fn save_state_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        std::fs::write(path, self.save_state())?;
+        Ok(())
+    }
+
+    pub fn load_state_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        let bytes = std::fs::read(path)?;
+        self.load_state(&bytes)
+    }
+
+    /// Ignored while a movie is playing, the movie's input takes priority.
+    pub fn set_active_buttons(&mut self, port: JoypadPort, buttons: HashSet<JoypadButton>) {
+        if self.is_playing_movie() {
+            return;
         }
+
+        self.cpu.bus.joypad_mut(port).active_buttons = buttons;
+    }
+
+    fn is_at_power_on(&self) -> bool {
+        let ppu = &self.cpu.bus.ppu;
+        self.frame_count == 0 && ppu.get_current_scanline() == 261 && ppu.get_current_dot() == 0
+    }
+
+    fn apply_movie_frame(&mut self, frame: &MovieFrame) {
+        self.cpu.bus.joypads[0].active_buttons = frame.buttons[0].clone();
+        self.cpu.bus.joypads[1].active_buttons = frame.buttons[1].clone();
+    }
+
+    /// Starts recording the input of every following frame. Unless the machine
+    /// was just powered on, the current state is embedded in the movie.
+    pub fn start_recording(&mut self, rom_filename: String) {
+        let start_state = if self.is_at_power_on() {
+            None
+        } else {
+            Some(self.save_state())
+        };
+
+        self.movie = Some(ActiveMovie {
+            mode: MovieMode::Recording(Movie::new(rom_filename, start_state)),
+            start_frame: self.frame_count,
+        });
     }
 
-    fn new() -> ScanlineCycleCounter {
-        ScanlineCycleCounter {
-            scanline_cycles_left: 341,
+    /// Returns the recorded movie, or `None` if nothing was being recorded.
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        match self.movie.take() {
+            Some(ActiveMovie {
+                mode: MovieMode::Recording(movie),
+                ..
+            }) => Some(movie),
+            other => {
+                self.movie = other;
+                None
+            }
+        }
+    }
+
+    /// Replays `movie` from its start state, or from power-on if it has none.
+    /// Once the movie runs out, input goes back to `set_active_buttons`.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        match &movie.start_state {
+            Some(state) => self
+                .load_state(state)
+                .map_err(MovieError::InvalidStartState)?,
+            None if !self.is_at_power_on() => return Err(MovieError::NotAtPowerOn),
+            None => {}
+        }
+
+        if let Some(frame) = movie.frames.first() {
+            self.apply_movie_frame(frame);
+        }
+
+        self.movie = Some(ActiveMovie {
+            mode: MovieMode::Playing(movie),
+            start_frame: self.frame_count,
+        });
+
+        Ok(())
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(
+            self.movie,
+            Some(ActiveMovie {
+                mode: MovieMode::Playing(_),
+                ..
+            })
+        )
+    }
+
+    // Called after loading a state. A recording forgets the frames after the state's
+    // frame, playback carries on from it. Movies that don't cover the state's frame end.
+    fn seek_movie(&mut self) {
+        let active_movie = match &mut self.movie {
+            Some(active_movie) => active_movie,
+            None => return,
+        };
+
+        let index = match self.frame_count.checked_sub(active_movie.start_frame) {
+            Some(index) => index as usize,
+            None => {
+                self.movie = None;
+                return;
+            }
+        };
+
+        match &mut active_movie.mode {
+            MovieMode::Recording(movie) if index <= movie.frames.len() => {
+                movie.frames.truncate(index)
+            }
+            MovieMode::Playing(movie) if index < movie.frames.len() => {
+                let frame = movie.frames[index].clone();
+                self.apply_movie_frame(&frame);
+            }
+            _ => self.movie = None,
+        }
+    }
+
+    // Called once a frame has been rendered: the buttons held during that frame
+    // are recorded, or the next frame's buttons are fed from the movie.
+    fn advance_movie(&mut self) {
+        let active_movie = match &mut self.movie {
+            Some(active_movie) => active_movie,
+            None => return,
+        };
+
+        let index = match self.frame_count.checked_sub(active_movie.start_frame) {
+            Some(index) => index as usize,
+            None => {
+                self.movie = None;
+                return;
+            }
+        };
+
+        match &mut active_movie.mode {
+            MovieMode::Recording(movie) => movie.frames.push(MovieFrame {
+                buttons: [
+                    self.cpu.bus.joypads[0].active_buttons.clone(),
+                    self.cpu.bus.joypads[1].active_buttons.clone(),
+                ],
+            }),
+            MovieMode::Playing(movie) => match movie.frames.get(index).cloned() {
+                Some(frame) => self.apply_movie_frame(&frame),
+                None => self.movie = None,
+            },
         }
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::apu::MemoryAudioSink;
+
+    // A `Machine` is a few hundred KiB of inline arrays, which overflows the
+    // default test thread stack in debug builds.
+    fn with_machine<F: FnOnce(&mut Machine) + Send + 'static>(test: F) {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| {
+                let mut machine = Machine::load(
+                    &"nestest.nes".to_string(),
+                    Apu::new(Box::new(MemoryAudioSink::new())),
+                )
+                .unwrap();
+
+                test(&mut machine);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn run_frames(machine: &mut Machine, frames: u32) {
+        let mut rendered = 0;
+        while rendered < frames {
+            if let Some(SideEffect::Render) = machine.step() {
+                rendered += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        with_machine(|machine| {
+            run_frames(machine, 10);
+            let state = machine.save_state();
+
+            run_frames(machine, 10);
+            let expected = machine.save_state();
+
+            machine.load_state(&state).unwrap();
+            assert_eq!(machine.save_state(), state);
+
+            run_frames(machine, 10);
+            assert_eq!(machine.save_state(), expected);
+        });
+    }
+
+    #[test]
+    fn rejects_corrupted_state() {
+        with_machine(|machine| {
+            let state = machine.save_state();
+
+            assert!(machine.load_state(&state[..state.len() - 1]).is_err());
+            assert!(machine.load_state(&state[4..]).is_err());
+            assert_eq!(machine.save_state(), state);
+        });
+    }
+
+    #[test]
+    fn movie_replays_recorded_input() {
+        with_machine(|machine| {
+            run_frames(machine, 5);
+            machine.start_recording("nestest.nes".to_string());
+
+            for frame in 0..10 {
+                let buttons = if frame % 2 == 0 {
+                    [JoypadButton::A, JoypadButton::Up]
+                        .iter()
+                        .copied()
+                        .collect()
+                } else {
+                    HashSet::new()
+                };
+                machine.set_active_buttons(JoypadPort::One, buttons);
+                run_frames(machine, 1);
+            }
+
+            let movie = machine.stop_recording().unwrap();
+            let expected = machine.save_state();
+
+            assert!(movie.start_state.is_some());
+            assert_eq!(movie.frames.len(), 10);
+            assert_eq!(machine.frame_count(), 15);
+
+            let movie = Movie::parse(&movie.to_fm2()).unwrap();
+            machine.set_active_buttons(JoypadPort::One, HashSet::new());
+            machine.play_movie(movie).unwrap();
+            assert_eq!(machine.frame_count(), 5);
+
+            run_frames(machine, 10);
+            assert!(!machine.is_playing_movie());
+            assert_eq!(machine.save_state(), expected);
+        });
+    }
+
+    #[test]
+    fn rewinding_a_recording_drops_later_frames() {
+        with_machine(|machine| {
+            machine.start_recording("nestest.nes".to_string());
+            run_frames(machine, 3);
+            let state = machine.save_state();
+
+            let buttons: HashSet<_> = [JoypadButton::B].iter().copied().collect();
+            machine.set_active_buttons(JoypadPort::One, buttons.clone());
+            run_frames(machine, 4);
+            machine.load_state(&state).unwrap();
+            machine.set_active_buttons(JoypadPort::One, HashSet::new());
+            run_frames(machine, 2);
+
+            let movie = machine.stop_recording().unwrap();
+            assert_eq!(movie.frames.len(), 5);
+            assert!(movie.frames.iter().all(|frame| frame.buttons[0].is_empty()));
+        });
+    }
+
+    #[test]
+    fn loading_state_from_before_movie_stops_it() {
+        with_machine(|machine| {
+            run_frames(machine, 4);
+            let state = machine.save_state();
+
+            run_frames(machine, 1);
+            machine.start_recording("nestest.nes".to_string());
+            run_frames(machine, 5);
+            machine.load_state(&state).unwrap();
+            assert!(machine.stop_recording().is_none());
+
+            run_frames(machine, 1);
+            assert_eq!(machine.frame_count(), 5);
+        });
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     collections::HashSet,
     convert::TryInto,
     env, io,
+    path::Path,
     time::{Duration, SystemTime},
 };
 
@@ -11,19 +12,26 @@ mod cpu;
 mod ines;
 mod instruction;
 mod machine;
+mod movie;
 mod ppu;
 mod ppu_debugger;
 mod render;
+mod rewind;
+mod save_state;
+mod sdl_audio;
 mod utils;
 
-use apu::Apu;
-use bus::{JoypadButton, MemoryBuffer};
-use machine::Machine;
+use apu::{Apu, MemoryAudioSink};
+use bus::{JoypadButton, JoypadPort, MemoryBuffer};
+use machine::{Machine, SideEffect};
+use movie::Movie;
 use ppu::VideoMemoryBuffer;
 use ppu_debugger::PpuDebugger;
 use render::Renderer;
+use rewind::RewindBuffer;
+use sdl_audio::SdlAudioSink;
 
-use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::keyboard::{KeyboardState, Keycode, Scancode};
 use termion::raw::IntoRawMode;
 use tui::{
     backend::TermionBackend,
@@ -116,9 +124,165 @@ fn video_ram_widget(buffer: &VideoMemoryBuffer) -> Table {
 
 const SCALE: u32 = 3;
 
+const PLAYER_1_KEYS: [(Scancode, JoypadButton); 8] = [
+    (Scancode::A, JoypadButton::A),
+    (Scancode::S, JoypadButton::B),
+    (Scancode::RShift, JoypadButton::Select),
+    (Scancode::Return, JoypadButton::Start),
+    (Scancode::Up, JoypadButton::Up),
+    (Scancode::Down, JoypadButton::Down),
+    (Scancode::Left, JoypadButton::Left),
+    (Scancode::Right, JoypadButton::Right),
+];
+
+const PLAYER_2_KEYS: [(Scancode, JoypadButton); 8] = [
+    (Scancode::M, JoypadButton::A),
+    (Scancode::N, JoypadButton::B),
+    (Scancode::Y, JoypadButton::Select),
+    (Scancode::U, JoypadButton::Start),
+    (Scancode::I, JoypadButton::Up),
+    (Scancode::K, JoypadButton::Down),
+    (Scancode::J, JoypadButton::Left),
+    (Scancode::L, JoypadButton::Right),
+];
+
+fn pressed_buttons(
+    keyboard_state: &KeyboardState,
+    keys: &[(Scancode, JoypadButton)],
+) -> HashSet<JoypadButton> {
+    keys.iter()
+        .filter(|(scancode, _)| keyboard_state.is_scancode_pressed(*scancode))
+        .map(|(_, button)| *button)
+        .collect()
+}
+
+// Snapshot every other frame and keep 20 seconds worth of them. Holding the
+// rewind key steps back one snapshot per rendered frame.
+const REWIND_INTERVAL: u32 = 2;
+const REWIND_CAPACITY: usize = 600;
+const REWIND_KEY: Scancode = Scancode::Backspace;
+
+// Flush battery-backed RAM roughly every 5 seconds, so a crash loses little progress.
+const BATTERY_FLUSH_INTERVAL: u32 = 300;
+
+const USAGE: &str = "Usage: mad-nes [--headless] [--record movie.fm2 | --play movie.fm2] [--no-sprite-limit] [/path/to/game.nes] [frames]";
+
+struct Options {
+    rom_path: String,
+    headless_frames: Option<u32>,
+    record_path: Option<String>,
+    play_path: Option<String>,
+    no_sprite_limit: bool,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut headless = false;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut no_sprite_limit = false;
+    let mut positional = Vec::new();
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless = true,
+            "--record" => record_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--play" => play_path = Some(args.next().ok_or(USAGE)?.clone()),
+            "--no-sprite-limit" => no_sprite_limit = true,
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    if record_path.is_some() && play_path.is_some() {
+        return Err("--record and --play can't be used together".into());
+    }
+
+    let headless_frames = match (headless, positional.len()) {
+        (false, 1) => None,
+        (true, 2) => Some(
+            positional[1]
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid frame count: {}", positional[1]))?,
+        ),
+        _ => return Err(USAGE.into()),
+    };
+
+    Ok(Options {
+        rom_path: positional.swap_remove(0),
+        headless_frames,
+        record_path,
+        play_path,
+        no_sprite_limit,
+    })
+}
+
 fn main() -> Result<(), String> {
     let args: Vec<String> = env::args().collect();
+    let options = parse_options(&args)?;
+
+    match options.headless_frames {
+        Some(frames) => run_headless(&options, frames),
+        None => run_frontend(&options),
+    }
+}
+
+/// Starts recording or playing back a movie, as requested on the command line.
+fn start_movie(machine: &mut Machine, options: &Options) -> Result<(), String> {
+    if let Some(play_path) = &options.play_path {
+        let movie = Movie::load_from_file(play_path)
+            .map_err(|error| format!("Failed loading movie {}: {}", play_path, error))?;
+        machine
+            .play_movie(movie)
+            .map_err(|error| format!("Failed playing movie {}: {}", play_path, error))?;
+    }
+
+    if options.record_path.is_some() {
+        let rom_filename = Path::new(&options.rom_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        machine.start_recording(rom_filename);
+    }
+
+    Ok(())
+}
 
+fn finish_movie(machine: &mut Machine, options: &Options) -> Result<(), String> {
+    if let (Some(record_path), Some(movie)) = (&options.record_path, machine.stop_recording()) {
+        movie
+            .save_to_file(record_path)
+            .map_err(|error| format!("Failed writing movie {}: {}", record_path, error))?;
+    }
+
+    Ok(())
+}
+
+/// Runs the emulator for a fixed number of frames without touching SDL,
+/// discarding the generated audio.
+fn run_headless(options: &Options, frames: u32) -> Result<(), String> {
+    let rom_path = &options.rom_path;
+    let audio_sink = MemoryAudioSink::new();
+    let mut machine = Machine::load(rom_path, Apu::new(Box::new(audio_sink.clone())))
+        .map_err(|error| format!("Failed loading {}: {}", rom_path, error))?;
+    machine.set_sprite_limit(!options.no_sprite_limit);
+    start_movie(&mut machine, options)?;
+
+    let end_frame = machine.frame_count() + frames as u64;
+    while machine.frame_count() < end_frame {
+        if let Some(SideEffect::Render) = machine.step() {
+            audio_sink.take_samples();
+        }
+    }
+
+    finish_movie(&mut machine, options)?;
+
+    machine
+        .flush_battery_save()
+        .map_err(|error| error.to_string())
+}
+
+fn run_frontend(options: &Options) -> Result<(), String> {
+    let rom_path = &options.rom_path;
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -153,7 +317,12 @@ fn main() -> Result<(), String> {
     // let debug_texture = debugger_canvas.texture_creator();
     // let mut debug_renderer = PpuDebugger::new(debugger_canvas, &debug_texture);
 
-    let mut machine = Machine::load(&args[1], Apu::new(sdl_context.audio().unwrap())).unwrap();
+    let audio_sink = SdlAudioSink::new(&sdl_context.audio()?)?;
+    let mut machine = Machine::load(rom_path, Apu::new(Box::new(audio_sink)))
+        .map_err(|error| format!("Failed loading {}: {}", rom_path, error))?;
+    machine.set_sprite_limit(!options.no_sprite_limit);
+    start_movie(&mut machine, options)?;
+    let state_path = Path::new(rom_path).with_extension("state");
     // let stdout = io::stdout()
     //     .into_raw_mode()
     //     .map_err(|_| "Failed retrieving stdout")?;
@@ -163,6 +332,8 @@ fn main() -> Result<(), String> {
     // terminal.clear().unwrap();
 
     let mut frame_counter = 0;
+    let mut frames_since_battery_flush = 0;
+    let mut rewind_buffer = RewindBuffer::new(REWIND_CAPACITY, REWIND_INTERVAL);
     let mut cpu_steps = 0;
 
     let mut start_time = std::time::SystemTime::now();
@@ -190,46 +361,50 @@ fn main() -> Result<(), String> {
         cpu_steps += 1;
 
         if let Some(side_effect) = side_effect {
-            let mut active_buttons = HashSet::<JoypadButton>::new();
-
             for event in event_pump.poll_iter() {
                 match event {
                     sdl2::event::Event::Quit { .. } => break 'running,
+                    sdl2::event::Event::KeyDown {
+                        keycode: Some(Keycode::F5),
+                        repeat: false,
+                        ..
+                    } => {
+                        if let Err(error) = machine.save_state_to_file(&state_path) {
+                            println!("Failed saving state: {}", error);
+                        }
+                    }
+                    sdl2::event::Event::KeyDown {
+                        keycode: Some(Keycode::F7),
+                        repeat: false,
+                        ..
+                    } => {
+                        if let Err(error) = machine.load_state_from_file(&state_path) {
+                            println!("Failed loading state: {}", error);
+                        }
+                    }
                     _ => {}
                 }
             }
 
-            for scancode in event_pump.keyboard_state().pressed_scancodes() {
-                match scancode {
-                    Scancode::A => {
-                        active_buttons.insert(JoypadButton::A);
+            let keyboard_state = event_pump.keyboard_state();
+            machine.set_active_buttons(
+                JoypadPort::One,
+                pressed_buttons(&keyboard_state, &PLAYER_1_KEYS),
+            );
+            machine.set_active_buttons(
+                JoypadPort::Two,
+                pressed_buttons(&keyboard_state, &PLAYER_2_KEYS),
+            );
+
+            if keyboard_state.is_scancode_pressed(REWIND_KEY) {
+                if let Some(state) = rewind_buffer.pop() {
+                    if let Err(error) = machine.load_state(&state) {
+                        println!("Failed rewinding: {}", error);
                     }
-                    Scancode::S => {
-                        active_buttons.insert(JoypadButton::B);
-                    }
-                    Scancode::RShift => {
-                        active_buttons.insert(JoypadButton::Select);
-                    }
-                    Scancode::Return => {
-                        active_buttons.insert(JoypadButton::Start);
-                    }
-                    Scancode::Up => {
-                        active_buttons.insert(JoypadButton::Up);
-                    }
-                    Scancode::Down => {
-                        active_buttons.insert(JoypadButton::Down);
-                    }
-                    Scancode::Left => {
-                        active_buttons.insert(JoypadButton::Left);
-                    }
-                    Scancode::Right => {
-                        active_buttons.insert(JoypadButton::Right);
-                    }
-                    _ => {}
                 }
+            } else {
+                rewind_buffer.frame_rendered(|| machine.save_state());
             }
-            // dbg!(&active_buttons);
-            machine.set_active_buttons(active_buttons);
 
             let frame_start = SystemTime::now();
             renderer.render(&machine.get_ppu());
@@ -239,6 +414,14 @@ fn main() -> Result<(), String> {
 
             frame_counter += 1;
 
+            frames_since_battery_flush += 1;
+            if frames_since_battery_flush == BATTERY_FLUSH_INTERVAL {
+                frames_since_battery_flush = 0;
+                if let Err(error) = machine.flush_battery_save() {
+                    println!("Failed writing battery save: {}", error);
+                }
+            }
+
             let last_render_time = std::time::SystemTime::now();
             let render_duration = last_render_time.duration_since(start_time).unwrap();
 
@@ -262,7 +445,11 @@ fn main() -> Result<(), String> {
         }
     }
 
-    Ok(())
+    finish_movie(&mut machine, options)?;
+
+    machine
+        .flush_battery_save()
+        .map_err(|error| format!("Failed writing battery save: {}", error))
 }
 
 #[cfg(test)]
@@ -0,0 +1,355 @@
+use std::{collections::HashSet, path::Path};
+
+use crate::{bus::JoypadButton, save_state::SaveStateError};
+
+// Order of the buttons in an FM2 gamepad field, e.g. "R..U...A".
+const FM2_BUTTONS: [(char, JoypadButton); 8] = [
+    ('R', JoypadButton::Right),
+    ('L', JoypadButton::Left),
+    ('D', JoypadButton::Down),
+    ('U', JoypadButton::Up),
+    ('T', JoypadButton::Start),
+    ('S', JoypadButton::Select),
+    ('B', JoypadButton::B),
+    ('A', JoypadButton::A),
+];
+
+// FCEUX embeds its own save state format under `savestate`, so ours goes under a separate key.
+const START_STATE_KEY: &str = "madNesSavestate";
+
+const HARD_RESET_COMMAND: u8 = 2;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(std::io::Error),
+    InvalidLine(usize),
+    UnsupportedCommand { frame: usize, command: u8 },
+    FceuxSavestate,
+    InvalidBase64,
+    InvalidStartState(SaveStateError),
+    NotAtPowerOn,
+}
+
+impl std::fmt::Display for MovieError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovieError::Io(error) => write!(f, "{}", error),
+            MovieError::InvalidLine(line) => write!(f, "invalid movie line {}", line),
+            MovieError::UnsupportedCommand { frame, command } => {
+                write!(f, "unsupported command {} at frame {}", command, frame)
+            }
+            MovieError::FceuxSavestate => {
+                write!(
+                    f,
+                    "movies starting from an FCEUX save state are not supported"
+                )
+            }
+            MovieError::InvalidBase64 => write!(f, "invalid base64 data"),
+            MovieError::InvalidStartState(error) => write!(f, "invalid start state: {}", error),
+            MovieError::NotAtPowerOn => {
+                write!(
+                    f,
+                    "movies without a start state must be played from power-on"
+                )
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for MovieError {
+    fn from(error: std::io::Error) -> MovieError {
+        MovieError::Io(error)
+    }
+}
+
+/// Controller input for both ports during one frame.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct MovieFrame {
+    pub buttons: [HashSet<JoypadButton>; 2],
+}
+
+/// A recording of per-frame controller input, stored as FCEUX's text `.fm2` format.
+/// A movie either starts at power-on, or from a save state embedded in the file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Movie {
+    pub rom_filename: String,
+    pub start_state: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: String, start_state: Option<Vec<u8>>) -> Movie {
+        Movie {
+            rom_filename,
+            start_state,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new(String::new(), None);
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+
+            if line.starts_with('|') {
+                let frame = parse_input_line(line, movie.frames.len())
+                    .ok_or(MovieError::InvalidLine(line_number))??;
+                movie.frames.push(frame);
+                continue;
+            }
+
+            let (key, value) = match line.find(' ') {
+                Some(position) => (&line[..position], &line[position + 1..]),
+                None => (line, ""),
+            };
+
+            match key {
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "savestate" => return Err(MovieError::FceuxSavestate),
+                START_STATE_KEY => {
+                    let data = value
+                        .strip_prefix("base64:")
+                        .ok_or(MovieError::InvalidLine(line_number))?;
+                    movie.start_state = Some(decode_base64(data)?);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+
+        text.push_str("version 3\n");
+        text.push_str("emuVersion 22020\n");
+        text.push_str("rerecordCount 0\n");
+        text.push_str("palFlag 0\n");
+        text.push_str(&format!("romFilename {}\n", self.rom_filename));
+        text.push_str("guid 00000000-0000-0000-0000-000000000000\n");
+        text.push_str("fourscore 0\n");
+        text.push_str("microphone 0\n");
+        text.push_str("port0 1\n");
+        text.push_str("port1 1\n");
+        text.push_str("port2 0\n");
+        text.push_str("FDS 0\n");
+        text.push_str("NewPPU 0\n");
+
+        if let Some(state) = &self.start_state {
+            text.push_str(&format!(
+                "{} base64:{}\n",
+                START_STATE_KEY,
+                encode_base64(state)
+            ));
+        }
+
+        for frame in &self.frames {
+            text.push_str(&format!(
+                "|0|{}|{}||\n",
+                gamepad_field(&frame.buttons[0]),
+                gamepad_field(&frame.buttons[1])
+            ));
+        }
+
+        text
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        std::fs::write(path, self.to_fm2())?;
+        Ok(())
+    }
+}
+
+fn gamepad_field(buttons: &HashSet<JoypadButton>) -> String {
+    FM2_BUTTONS
+        .iter()
+        .map(|(letter, button)| {
+            if buttons.contains(button) {
+                *letter
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+// Any character other than '.' or ' ' marks a pressed button.
+fn parse_gamepad_field(field: &str) -> Option<HashSet<JoypadButton>> {
+    if field.is_empty() {
+        return Some(HashSet::new());
+    }
+
+    if field.chars().count() != 8 {
+        return None;
+    }
+
+    Some(
+        field
+            .chars()
+            .zip(FM2_BUTTONS.iter())
+            .filter(|(character, _)| *character != '.' && *character != ' ')
+            .map(|(_, (_, button))| *button)
+            .collect(),
+    )
+}
+
+// Returns `None` for a malformed line, and an error for valid commands we can't replay.
+// A hard reset on the first frame is the same as starting from power-on.
+fn parse_input_line(line: &str, frame: usize) -> Option<Result<MovieFrame, MovieError>> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 4 {
+        return None;
+    }
+
+    let command = fields[1].trim().parse::<u8>().ok()?;
+    if command != 0 && !(frame == 0 && command == HARD_RESET_COMMAND) {
+        return Some(Err(MovieError::UnsupportedCommand { frame, command }));
+    }
+
+    Some(Ok(MovieFrame {
+        buttons: [
+            parse_gamepad_field(fields[2])?,
+            parse_gamepad_field(fields[3])?,
+        ],
+    }))
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let value = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                let sextet = (value >> (18 - index * 6)) & 0x3f;
+                text.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, MovieError> {
+    let text = text.trim().trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+
+    let mut value = 0u32;
+    let mut bits = 0;
+
+    for character in text.bytes() {
+        let sextet = BASE64_ALPHABET
+            .iter()
+            .position(|letter| *letter == character)
+            .ok_or(MovieError::InvalidBase64)?;
+
+        value = value << 6 | sextet as u32;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((value >> bits) as u8);
+            value &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_round_trip() {
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length)
+                .map(|value: u8| value.wrapping_mul(37))
+                .collect();
+            assert_eq!(decode_base64(&encode_base64(&bytes)).unwrap(), bytes);
+        }
+
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+    }
+
+    #[test]
+    fn parse_fm2() {
+        let text = "version 3\n\
+                    romFilename Super Mario Bros.\n\
+                    port0 1\n\
+                    |2|........|........||\n\
+                    |0|R......A|...U....||\n\
+                    |0|  D   B |||\n";
+
+        let movie = Movie::parse(text).unwrap();
+
+        assert_eq!(movie.rom_filename, "Super Mario Bros.");
+        assert_eq!(movie.start_state, None);
+        assert_eq!(movie.frames.len(), 3);
+        assert!(movie.frames[0].buttons[0].is_empty());
+        assert_eq!(
+            movie.frames[1].buttons[0],
+            [JoypadButton::Right, JoypadButton::A]
+                .iter()
+                .copied()
+                .collect()
+        );
+        assert_eq!(
+            movie.frames[1].buttons[1],
+            [JoypadButton::Up].iter().copied().collect()
+        );
+        assert_eq!(
+            movie.frames[2].buttons[0],
+            [JoypadButton::Down, JoypadButton::B]
+                .iter()
+                .copied()
+                .collect()
+        );
+        assert!(movie.frames[2].buttons[1].is_empty());
+    }
+
+    #[test]
+    fn reject_soft_reset() {
+        let text = "|0|........|||\n|1|........|||\n";
+
+        assert!(matches!(
+            Movie::parse(text),
+            Err(MovieError::UnsupportedCommand {
+                frame: 1,
+                command: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn fm2_round_trip() {
+        let mut movie = Movie::new("game".to_string(), Some(vec![1, 2, 3, 4, 5]));
+        movie.frames.push(MovieFrame::default());
+        movie.frames.push(MovieFrame {
+            buttons: [
+                [JoypadButton::Start].iter().copied().collect(),
+                [JoypadButton::Left, JoypadButton::Select]
+                    .iter()
+                    .copied()
+                    .collect(),
+            ],
+        });
+
+        assert_eq!(Movie::parse(&movie.to_fm2()).unwrap(), movie);
+    }
+}
@@ -1,11 +1,13 @@
 use std::{
     cell::{Ref, RefCell},
-    convert::TryInto,
-    ops::{BitAnd, Shr},
     rc::Rc,
 };
 
-use crate::{ines::Cartridge, log_ppu};
+use crate::{
+    ines::Cartridge,
+    log_ppu,
+    save_state::{SaveStateError, Snapshot, StateReader, StateWriter},
+};
 
 use bitflags::bitflags;
 
@@ -51,6 +53,15 @@ pub struct SpriteData {
     pub drawing_mode: SpriteDrawingMode,
 }
 
+// One pixel of the sprites drawn on the current line, `pattern` 0 being transparent.
+#[derive(Clone, Copy, Default)]
+struct SpritePixel {
+    pattern: u8,
+    palette: u8,
+    behind_background: bool,
+    is_sprite_zero: bool,
+}
+
 bitflags! {
     pub struct PpuControl: u8 {
         const GENERATE_NMI_AT_VBLANK = 0b10000000;
@@ -125,11 +136,24 @@ bitflags! {
     }
 }
 
-pub enum ScanlineEffect {
-    EnterVblank,
+/// Where one of the four 1 KiB nametable slots at $2000-$2FFF is read from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NametableSource {
+    /// One of the two 1 KiB pages of the console's own VRAM.
+    Ciram(u8),
+    /// A 1 KiB page of nametable RAM on the cartridge, e.g. four-screen VRAM.
+    CartridgeRam(u8),
+    /// A 1 KiB bank of CHR ROM, or CHR-RAM on boards without ROM.
+    Chr(u16),
+    /// Supplied by the mapper itself through `Mapper::read_nametable`.
+    Mapper,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// The sources of the nametables at $2000, $2400, $2800 and $2C00.
+pub type NametableLayout = [NametableSource; 4];
+
+/// The fixed layouts selectable by a header or a simple mapper register.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
@@ -138,12 +162,14 @@ pub enum Mirroring {
 }
 
 impl Mirroring {
-    fn real_address(&self, address: u16) -> u16 {
+    pub fn layout(&self) -> NametableLayout {
+        use NametableSource::Ciram;
+
         match self {
-            Mirroring::Vertical => (address & 0x23ff) | (address & 0x400),
-            Mirroring::Horizontal => (address & 0x23ff) | ((address / 2) & 0x400),
-            Mirroring::OneScreenLow => address & 0x23ff,
-            Mirroring::OneScreenHigh => (address & 0x23ff) | 0x400,
+            Mirroring::Horizontal => [Ciram(0), Ciram(0), Ciram(1), Ciram(1)],
+            Mirroring::Vertical => [Ciram(0), Ciram(1), Ciram(0), Ciram(1)],
+            Mirroring::OneScreenLow => [Ciram(0); 4],
+            Mirroring::OneScreenHigh => [Ciram(1); 4],
         }
     }
 }
@@ -152,6 +178,8 @@ pub struct Ppu {
     memory: VideoMemoryBuffer,
     write_latch: WriteLatch,
     status: PpuStatus,
+    // Set by a $2002 read on the dot vblank starts, which keeps the flag from being set.
+    vblank_suppressed: bool,
 
     current_oam_address: u8,
     oam_data: [u8; 256],
@@ -159,6 +187,10 @@ pub struct Ppu {
     mask: PpuMask,
 
     read_buffer: u8,
+    // Last value on the data bus between the CPU and the PPU, which is what reads
+    // of write-only registers return. Each bit fades to 0 on its own.
+    io_latch: u8,
+    io_latch_refreshed: [u64; 8],
 
     x: u8,
     t: u16,
@@ -166,11 +198,34 @@ pub struct Ppu {
 
     current_scanline: u32,
     current_dot: u32,
-    current_fine_x: u8,
+
+    // Background pipeline: the latches filled by this tile's fetches, and the
+    // shift registers holding the two tiles being drawn.
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
+
+    // Total dots since power-on, which mappers use to time PPU bus activity.
+    cycle: u64,
+    // Dot of the current line at which sprite evaluation finds a ninth sprite.
+    sprite_overflow_dot: Option<u32>,
+    sprite_limit: bool,
+    // Copies of the OAM entries of the sprites on the next line, like secondary OAM.
+    // Only the first eight are fetched at dots 257-320, the rest are drawn when the
+    // limit is off.
+    line_sprites: [[u8; 4]; 64],
+    line_sprite_count: usize,
+    line_has_sprite_zero: bool,
+    // Low plane of the sprite pattern being fetched.
+    sprite_pattern_low: u8,
 
     frame_buffer: [[u8; 256]; 240],
-    foreground_sprite_buffer: [[u8; 256]; 240],
-    background_sprite_buffer: [[u8; 256]; 240],
+    sprite_line: [SpritePixel; 256],
 
     cartridge: Rc<RefCell<Cartridge>>,
 }
@@ -193,6 +248,16 @@ pub enum SpriteDrawingMode {
     Draw8x16,
 }
 
+// About 600 ms at the NTSC dot rate, how long a bit of the I/O latch stays set
+// after it was last driven high.
+const IO_LATCH_DECAY_DOTS: u64 = 5_369_318 * 6 / 10;
+
+// Bits 2-4 of a sprite's attribute byte don't exist in OAM and always read back as 0.
+const OAM_ATTRIBUTE_MASK: u8 = 0xe3;
+
+// The console's 2 KiB of nametable RAM, laid out by the cartridge's `NametableLayout`.
+const CIRAM_RANGE: std::ops::Range<usize> = 0x2000..0x2800;
+
 fn map_mirror(address: u16) -> u16 {
     match address {
         0x3000..=0x3eff => address - 0x1000,
@@ -215,23 +280,45 @@ impl Ppu {
             v: 0,
 
             read_buffer: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
 
             frame_buffer: [[0; 256]; 240],
             mask: PpuMask::empty(),
 
             status: PpuStatus::empty(),
+            vblank_suppressed: false,
 
             current_scanline: 261,
             current_dot: 0,
-            current_fine_x: 0,
+            next_tile: 0,
+            next_attribute: 0,
+            next_pattern_low: 0,
+            next_pattern_high: 0,
+            pattern_shift_low: 0,
+            pattern_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
+            cycle: 0,
+            sprite_overflow_dot: None,
+            sprite_limit: true,
+            line_sprites: [[0; 4]; 64],
+            line_sprite_count: 0,
+            line_has_sprite_zero: false,
+            sprite_pattern_low: 0,
 
             cartridge,
 
-            foreground_sprite_buffer: [[0xff; 256]; 240],
-            background_sprite_buffer: [[0xff; 256]; 240],
+            sprite_line: [SpritePixel::default(); 256],
         }
     }
 
+    /// Draws every sprite on a line instead of only the first eight. The sprite
+    /// overflow flag is still set as on hardware, so games that rely on it keep working.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
     pub fn get_status(&mut self) -> PpuStatus {
         self.status
     }
@@ -264,11 +351,45 @@ impl Ppu {
             self.status
         );
         self.clear_address_latch();
-        let bits = self.status.bits();
+        // Racing the flag reads it as clear and keeps it from being set, so no NMI
+        // happens this frame. A read right after it's set cancels the NMI on the CPU side.
+        if self.current_scanline == 241 && self.current_dot == 1 {
+            self.vblank_suppressed = true;
+        }
+
+        let bits = self.status.bits() | (self.read_io_latch() & 0x1f);
+        self.drive_io_latch(bits, 0xe0);
         self.status.remove(PpuStatus::IN_VBLANK);
         bits
     }
 
+    /// Every CPU write to $2000-$2007 fills the whole I/O latch.
+    pub fn write_io_latch(&mut self, value: u8) {
+        self.drive_io_latch(value, 0xff);
+    }
+
+    /// What reading one of the write-only registers returns.
+    pub fn read_io_latch(&mut self) -> u8 {
+        for bit in 0..8 {
+            if self.cycle.saturating_sub(self.io_latch_refreshed[bit]) >= IO_LATCH_DECAY_DOTS {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+
+        self.io_latch
+    }
+
+    // Puts `value` on the I/O bus for the bits in `mask`, the others keep decaying.
+    fn drive_io_latch(&mut self, value: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (value & mask);
+
+        for bit in 0..8 {
+            if mask & (1 << bit) != 0 {
+                self.io_latch_refreshed[bit] = self.cycle;
+            }
+        }
+    }
+
     pub fn clear_address_latch(&mut self) {
         self.write_latch = WriteLatch::Zero;
     }
@@ -284,17 +405,24 @@ impl Ppu {
 
     pub fn write_oam_data(&mut self, data: u8) {
         log_ppu!("[{:#03}] Write $2004: {:#04X}", self.current_scanline, data);
-        self.oam_data[self.current_oam_address as usize] = data;
+        self.store_oam_byte(self.current_oam_address, data);
         self.current_oam_address = self.current_oam_address.wrapping_add(1);
     }
 
-    pub fn read_oam_data(&self) -> u8 {
-        log_ppu!(
-            "[{:#03}] Read $2004: {:#04X}",
-            self.current_scanline,
-            self.oam_data[self.current_oam_address as usize]
-        );
-        self.oam_data[self.current_oam_address as usize]
+    pub fn read_oam_data(&mut self) -> u8 {
+        let value = self.oam_data[self.current_oam_address as usize];
+        log_ppu!("[{:#03}] Read $2004: {:#04X}", self.current_scanline, value);
+
+        self.drive_io_latch(value, 0xff);
+        value
+    }
+
+    fn store_oam_byte(&mut self, address: u8, value: u8) {
+        self.oam_data[address as usize] = if address & 3 == 2 {
+            value & OAM_ATTRIBUTE_MASK
+        } else {
+            value
+        };
     }
 
     pub fn read_data(&mut self) -> u8 {
@@ -308,34 +436,33 @@ impl Ppu {
             last_buffer
         );
 
+        // Palette entries are 6 bits wide, the top two come from the I/O latch.
         if self.v >= 0x3f00 {
-            let value = self.memory[real_address as usize];
-            self.read_buffer = self.memory[self.v as usize - 0x1000];
+            let value = (self.memory[real_address as usize] & 0x3f) | (self.read_io_latch() & 0xc0);
+            self.drive_io_latch(value, 0x3f);
+            // The buffer gets the nametable byte underneath, but that isn't a fetch the
+            // mapper sees.
+            self.read_buffer = self.peek_nametable(self.v & 0x2fff);
 
             self.v = self.v.wrapping_add(self.control.address_increment());
-            if self.v >= 0x1000 && self.v < 0x2000 {
-                // println!("Clock counter!");
-                self.cartridge.borrow_mut().scanline_tick();
-            }
+            self.cartridge
+                .borrow_mut()
+                .ppu_bus_address(self.v, self.cycle);
 
             value
         } else {
-            let mut cartridge = self.cartridge.borrow_mut();
             let value = if real_address < 0x2000 {
-                cartridge
-                    .read_chr_rom(real_address)
-                    .unwrap_or(self.memory[real_address as usize])
+                self.fetch_pattern_at_address(real_address)
             } else {
-                let real_address = cartridge.mirroring().real_address(real_address);
-                self.memory[real_address as usize]
+                self.read_nametable(real_address)
             };
+            self.drive_io_latch(last_buffer, 0xff);
+
+            let mut cartridge = self.cartridge.borrow_mut();
 
             self.read_buffer = value;
             self.v = self.v.wrapping_add(self.control.address_increment());
-            if self.v >= 0x1000 && self.v < 0x2000 {
-                // println!("Clock counter!");
-                cartridge.scanline_tick();
-            }
+            cartridge.ppu_bus_address(self.v, self.cycle);
 
             last_buffer
         }
@@ -351,7 +478,17 @@ impl Ppu {
 
         let real_address = map_mirror(self.v);
 
-        self.memory[real_address as usize] = data;
+        if real_address < 0x2000 {
+            self.cartridge.borrow_mut().write_chr(real_address, data);
+        } else if real_address < 0x3f00 {
+            self.cartridge.borrow_mut().write_nametable(
+                real_address,
+                data,
+                &mut self.memory[CIRAM_RANGE],
+            );
+        } else {
+            self.memory[real_address as usize] = data;
+        }
 
         if real_address == 0x3f00 {
             self.memory[0x3f10] = data;
@@ -359,21 +496,10 @@ impl Ppu {
             self.memory[0x3f00] = data;
         }
 
-        if real_address < 0x3f00 && real_address >= 0x2000 {
-            let real_address = self
-                .cartridge
-                .borrow()
-                .mirroring()
-                .real_address(real_address);
-            self.memory[real_address as usize] = data;
-        }
-
         self.v = self.v.wrapping_add(self.control.address_increment());
-
-        if self.v >= 0x1000 && self.v < 0x2000 {
-            // println!("Clock counter!");
-            self.cartridge.borrow_mut().scanline_tick();
-        }
+        self.cartridge
+            .borrow_mut()
+            .ppu_bus_address(self.v, self.cycle);
     }
 
     pub fn write_scroll(&mut self, position: u8) {
@@ -402,20 +528,19 @@ impl Ppu {
         self.write_latch.flip();
     }
 
-    pub fn copy_oam_data(&mut self, data: &[u8]) {
-        for index in 0..=255 {
-            self.oam_data[self.current_oam_address.wrapping_add(index) as usize] =
-                data[index as usize];
-        }
+    fn get_oam_sprite_data_at(&self, index: usize) -> SpriteData {
+        let base_index = self.current_oam_address as usize + index;
+        self.sprite_data([
+            self.oam_data[base_index % 256],
+            self.oam_data[(base_index + 1) % 256],
+            self.oam_data[(base_index + 2) % 256],
+            self.oam_data[(base_index + 3) % 256],
+        ])
     }
 
-    fn get_oam_sprite_data_at(&self, index: usize) -> SpriteData {
+    // Decodes the four bytes of an OAM entry.
+    fn sprite_data(&self, [y, byte1, byte2, x]: [u8; 4]) -> SpriteData {
         let drawing_mode = self.control.drawing_mode();
-        let base_index = (self.current_oam_address as usize + index) % 256;
-        let y = self.oam_data[base_index];
-        let x = self.oam_data[(base_index + 3) % 256];
-
-        let byte1 = self.oam_data[(base_index + 1) % 256];
         let tile_pattern = if drawing_mode == SpriteDrawingMode::Draw8x8 {
             self.control.sprite_pattern_table()
         } else {
@@ -432,7 +557,6 @@ impl Ppu {
             byte1 & !1
         };
 
-        let byte2 = self.oam_data[(base_index + 2) % 256];
         let color_palette = byte2 & 0b00000011;
 
         let draw_priority = if byte2 & 0b00100000 == 0 {
@@ -481,22 +605,34 @@ impl Ppu {
                 self.t &= 0xff00;
                 self.t |= address as u16;
                 self.v = self.t;
-
-                if self.v >= 0x1000 && self.v < 0x2000 {
-                    // println!("Clock counter!");
-                    self.cartridge.borrow_mut().scanline_tick();
-                }
+                self.cartridge
+                    .borrow_mut()
+                    .ppu_bus_address(self.v, self.cycle);
             }
         }
 
         self.write_latch.flip();
     }
 
-    fn read_pattern_at_address(&self, address: u16) -> u8 {
+    fn read_nametable(&self, address: u16) -> u8 {
+        self.cartridge
+            .borrow_mut()
+            .fetch_nametable(address, &self.memory[CIRAM_RANGE])
+    }
+
+    /// Reads a nametable byte without the mapper noticing, e.g. for debugging views.
+    pub fn peek_nametable(&self, address: u16) -> u8 {
         self.cartridge
             .borrow()
-            .read_chr_rom(address)
-            .unwrap_or(self.memory[address as usize])
+            .read_nametable(address, &self.memory[CIRAM_RANGE])
+    }
+
+    fn read_pattern_at_address(&self, address: u16) -> u8 {
+        self.cartridge.borrow().read_chr(address)
+    }
+
+    fn fetch_pattern_at_address(&self, address: u16) -> u8 {
+        self.cartridge.borrow_mut().fetch_chr(address)
     }
 
     pub fn read_pattern_value(
@@ -506,20 +642,21 @@ impl Ppu {
         x: u8,
         y: u16,
     ) -> u8 {
-        let mut address: u16 = 0;
-
-        if pattern_selection == PatternTableSelection::Right {
-            address += 0x1000;
-        }
-
-        address += tile_number as u16 * 0x10 + y as u16;
+        let address = pattern_address(pattern_selection, tile_number, y);
 
-        let pattern1 = self.read_pattern_at_address(address);
-        let pattern2 = self.read_pattern_at_address(address + 8);
-
-        let shift = 7 - x;
+        pattern_bit(
+            self.read_pattern_at_address(address),
+            self.read_pattern_at_address(address + 8),
+            x,
+        )
+    }
 
-        ((pattern1 >> shift) & 1) + ((pattern2 >> shift) & 1) * 2
+    // A pattern fetch made while rendering, which also puts the address on the bus
+    // for mappers watching A12.
+    fn fetch_pattern(&self, address: u16) -> u8 {
+        let mut cartridge = self.cartridge.borrow_mut();
+        cartridge.ppu_bus_address(address, self.cycle);
+        cartridge.fetch_chr(address)
     }
 
     pub fn get_buffer(&self) -> &VideoMemoryBuffer {
@@ -586,138 +723,50 @@ impl Ppu {
 
     pub fn step(&mut self) -> bool {
         let mut should_render = false;
-        match (self.current_scanline, self.current_dot) {
-            (261, 1) => {
-                self.background_sprite_buffer = [[0xff; 256]; 240];
-                self.foreground_sprite_buffer = [[0xff; 256]; 240];
 
-                self.status.remove(PpuStatus::IN_VBLANK);
-                self.status.remove(PpuStatus::SPRITE_0_HIT);
+        if self.current_dot == 0 {
+            self.cartridge
+                .borrow_mut()
+                .scanline_started(self.current_scanline, self.mask.is_rendering_enabled());
+        }
 
-                if self.mask.is_rendering_enabled() {
-                    self.v &= !0b111101111100000;
-                    self.v |= self.t & 0b111101111100000;
-                }
-            }
-            (0..=239, 0..=255) => {
-                if self.mask.is_rendering_enabled() {
-                    let palette = self.get_color_palette();
-
-                    let fine_y = (self.v & 0x7000) >> 12;
-                    // render
-                    let tile_address = 0x2000 | (self.v & 0xfff);
-                    let tile_address = self
-                        .cartridge
-                        .borrow()
-                        .mirroring()
-                        .real_address(tile_address);
-
-                    let coarse_x = self.v & 0b11111;
-                    let coarse_y = (self.v >> 5) & 0b11111;
-                    let tile_value = self.memory[tile_address as usize];
-
-                    let attribute_address = 0x23C0
-                        | (self.v & 0x0C00)
-                        | ((self.v >> 4) & 0x38)
-                        | ((self.v >> 2) & 0x07);
-                    let attribute_address = self
-                        .cartridge
-                        .borrow()
-                        .mirroring()
-                        .real_address(attribute_address);
-
-                    let attribute_value = self.memory[attribute_address as usize];
-                    let subtile_y = (coarse_y % 4) / 2;
-                    let subtile_x = (coarse_x % 4) / 2;
-
-                    let palette_set_index = match (subtile_x, subtile_y) {
-                        (0, 0) => attribute_value & 0b11,
-                        (1, 0) => attribute_value.bitand(0b1100 as u8) >> 2,
-                        (0, 1) => attribute_value.bitand(0b110000 as u8) >> 4,
-                        (1, 1) => attribute_value.bitand(0b11000000 as u8) >> 6,
-                        _ => panic!("Impossible subtile location!"),
-                    };
-
-                    let palette_value = palette.background_color_set[palette_set_index as usize];
-
-                    let bit = self.read_pattern_value(
-                        self.current_background_pattern_table(),
-                        tile_value,
-                        self.current_fine_x,
-                        fine_y,
-                    );
-
-                    // dbg!(bit);
-
-                    let color: u8 = if bit == 0
-                        || (self.current_dot < 8
-                            && !self.mask.contains(PpuMask::SHOW_LEFTMOST_BACKGROUND))
-                    {
-                        0xff
-                    } else {
-                        palette_value[bit as usize - 1]
-                    };
-
-                    self.frame_buffer[self.current_scanline as usize][self.current_dot as usize] =
-                        color;
-
-                    // move x
-                    if self.current_fine_x == 7 {
-                        self.current_fine_x = 0;
-
-                        if (self.v & 0x001F) == 31 {
-                            self.v &= !0x001f;
-                            self.v ^= 0x400;
-                        } else {
-                            self.v += 1;
-                        }
-                    } else {
-                        self.current_fine_x += 1;
-                    }
-                }
-            }
-            (0..=239, 256) => {
-                self.render_scanline_sprite();
+        if self.current_scanline < 240 && self.current_dot == 65 && self.mask.is_rendering_enabled()
+        {
+            self.sprite_overflow_dot = self.evaluate_sprite_overflow();
+        }
 
-                if self.is_background_rendering_enabled() {
-                    self.v &= !0b10000011111;
-                    self.v |= self.t & 0b10000011111;
+        if self.sprite_overflow_dot == Some(self.current_dot) {
+            self.status.insert(PpuStatus::SPRITE_OVERFLOW);
+            self.sprite_overflow_dot = None;
+        }
 
-                    self.current_fine_x = self.x;
-                }
+        let is_rendering_line = self.current_scanline < 240 || self.current_scanline == 261;
+        if is_rendering_line && self.mask.is_rendering_enabled() {
+            self.step_background();
+        }
+
+        match (self.current_scanline, self.current_dot) {
+            (261, 1) => {
+                self.status.remove(PpuStatus::IN_VBLANK);
+                self.status.remove(PpuStatus::SPRITE_0_HIT);
+                self.status.remove(PpuStatus::SPRITE_OVERFLOW);
             }
-            (0..=239, 257) => {
-                self.toggle_sprite_0_hit_if_needed();
-
-                if self.is_background_rendering_enabled() {
-                    if self.v & 0x7000 != 0x7000 {
-                        self.v += 0x1000;
-                    } else {
-                        self.v &= !0x7000;
-                        let mut y = (self.v & 0x03E0) >> 5;
-
-                        if y == 29 {
-                            y = 0;
-                            self.v ^= 0x0800;
-                        } else if y == 31 {
-                            y = 0;
-                        } else {
-                            y += 1;
-                        }
-
-                        self.v = (self.v & !0x03E0) | (y << 5);
-                    }
-                }
+            (0..=239, 1..=256) => self.render_pixel(self.current_dot as usize - 1),
+            (0..=239, 257) | (261, 257) => self.evaluate_sprite_line(),
+            (0..=239, 258..=320) | (261, 258..=320) if self.mask.is_rendering_enabled() => {
+                self.step_sprite_fetch()
             }
             (241, 1) => {
-                self.status.insert(PpuStatus::IN_VBLANK);
+                if !std::mem::replace(&mut self.vblank_suppressed, false) {
+                    self.status.insert(PpuStatus::IN_VBLANK);
+                }
                 should_render = true
             }
-            (0..=239, 260) => self.scanline_tick_if_possible(),
-            (261, 260) => self.scanline_tick_if_possible(),
             _ => {}
         }
 
+        self.cycle += 1;
+
         if self.current_dot == 340 {
             self.current_scanline = (self.current_scanline + 1) % 262;
             self.current_dot = 0;
@@ -728,151 +777,328 @@ impl Ppu {
         should_render
     }
 
-    fn render_scanline_sprite(&mut self) {
-        let sprites = self.get_all_oam_sprite_data();
-        let sprite_height = if self.control.contains(PpuControl::SPRITE_8X16_MODE) {
-            15
+    /// One dot of the background pipeline. Every 8 dots the PPU fetches a tile's
+    /// nametable, attribute and two pattern bytes into latches, which are loaded
+    /// into the low half of 16-bit shift registers. Each dot shifts them by one and
+    /// the pixel comes out at bit 15 minus fine X, so the first two tiles of a line
+    /// are prefetched at the end of the previous one, at dots 321-336.
+    fn step_background(&mut self) {
+        let dot = self.current_dot;
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+
+            if dot & 7 == 1 {
+                self.reload_background_shifters();
+            }
+        }
+
+        match dot {
+            1..=256 | 321..=336 => match (dot - 1) % 8 {
+                0 => self.next_tile = self.read_nametable(0x2000 | (self.v & 0xfff)),
+                2 => {
+                    let address = 0x23c0
+                        | (self.v & 0x0c00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    // Bit 1 of coarse X and bit 1 of coarse Y pick the quadrant.
+                    let shift = ((self.v >> 4) & 4) | (self.v & 2);
+                    self.next_attribute = (self.read_nametable(address) >> shift) & 0b11;
+                }
+                4 => self.next_pattern_low = self.fetch_pattern(self.background_pattern_address()),
+                6 => {
+                    self.next_pattern_high =
+                        self.fetch_pattern(self.background_pattern_address() + 8)
+                }
+                7 => self.increment_coarse_x(),
+                _ => {}
+            },
+            // Two nametable fetches whose results are never used, which MMC5 counts.
+            337 | 339 => {
+                self.read_nametable(0x2000 | (self.v & 0xfff));
+            }
+            _ => {}
+        }
+
+        match dot {
+            256 => self.increment_y(),
+            257 => self.v = (self.v & !0x041f) | (self.t & 0x041f),
+            280..=304 if self.current_scanline == 261 => {
+                self.v = (self.v & !0x7be0) | (self.t & 0x7be0)
+            }
+            _ => {}
+        }
+    }
+
+    fn shift_background(&mut self) {
+        self.pattern_shift_low <<= 1;
+        self.pattern_shift_high <<= 1;
+        self.attribute_shift_low <<= 1;
+        self.attribute_shift_high <<= 1;
+    }
+
+    fn reload_background_shifters(&mut self) {
+        // Both attribute bits are the same for all 8 pixels of a tile.
+        let spread = |bit: bool| if bit { 0xff } else { 0 };
+
+        self.pattern_shift_low = (self.pattern_shift_low & 0xff00) | self.next_pattern_low as u16;
+        self.pattern_shift_high =
+            (self.pattern_shift_high & 0xff00) | self.next_pattern_high as u16;
+        self.attribute_shift_low =
+            (self.attribute_shift_low & 0xff00) | spread(self.next_attribute & 1 != 0);
+        self.attribute_shift_high =
+            (self.attribute_shift_high & 0xff00) | spread(self.next_attribute & 2 != 0);
+    }
+
+    /// Picks the final color of pixel `x` on the current line from the background
+    /// shift registers and the sprites evaluated for this line. The first opaque sprite
+    /// in OAM wins over later ones even when it is behind the background.
+    fn render_pixel(&mut self, x: usize) {
+        let backdrop = self.memory[0x3f00];
+
+        if !self.mask.is_rendering_enabled() {
+            self.frame_buffer[self.current_scanline as usize][x] = backdrop & 0x3f;
+            return;
+        }
+
+        let bit = 0x8000 >> self.x;
+        let mut background = ((self.pattern_shift_high & bit != 0) as usize) << 1
+            | (self.pattern_shift_low & bit != 0) as usize;
+        let background_palette = ((self.attribute_shift_high & bit != 0) as usize) << 1
+            | (self.attribute_shift_low & bit != 0) as usize;
+
+        if !self.mask.contains(PpuMask::SHOW_BACKGROUND)
+            || (x < 8 && !self.mask.contains(PpuMask::SHOW_LEFTMOST_BACKGROUND))
+        {
+            background = 0;
+        }
+
+        let mut sprite = self.sprite_line[x];
+        if !self.mask.contains(PpuMask::SHOW_SPRITES)
+            || (x < 8 && !self.mask.contains(PpuMask::SHOW_LEFTMOST_SPRITES))
+        {
+            sprite.pattern = 0;
+        }
+
+        if sprite.is_sprite_zero && sprite.pattern != 0 && background != 0 && x != 255 {
+            self.status.insert(PpuStatus::SPRITE_0_HIT);
+        }
+
+        let color = if sprite.pattern != 0 && (background == 0 || !sprite.behind_background) {
+            self.memory[0x3f10 + sprite.palette as usize * 4 + sprite.pattern as usize]
+        } else if background != 0 {
+            self.memory[0x3f00 + background_palette * 4 + background]
         } else {
-            7
+            backdrop
         };
 
-        let mut secondary_oam_indexes: Vec<usize> = Vec::new();
-        let palette = self.get_color_palette().sprite_color_set;
+        self.frame_buffer[self.current_scanline as usize][x] = color & 0x3f;
+    }
 
-        // handle flips horizontal, flip vertical, 8x16
-        // dbg!(&sprites);
-        secondary_oam_indexes.clear();
-        for index in 0..sprites.len() {
-            let sprite = &sprites[index];
+    fn background_pattern_address(&self) -> u16 {
+        let fine_y = (self.v & 0x7000) >> 12;
+        pattern_address(
+            self.current_background_pattern_table(),
+            self.next_tile,
+            fine_y,
+        )
+    }
 
-            if sprite.y as u32 + 1 <= self.current_scanline
-                && self.current_scanline <= sprite.y as u32 + 1 + sprite_height
-            {
-                secondary_oam_indexes.push(index);
-            }
+    fn increment_coarse_x(&mut self) {
+        if (self.v & 0x001f) == 31 {
+            self.v &= !0x001f;
+            self.v ^= 0x400;
+        } else {
+            self.v += 1;
+        }
+    }
 
-            if secondary_oam_indexes.len() == 8 {
-                break;
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+            let mut y = (self.v & 0x03e0) >> 5;
+
+            if y == 29 {
+                y = 0;
+                self.v ^= 0x0800;
+            } else if y == 31 {
+                y = 0;
+            } else {
+                y += 1;
             }
+
+            self.v = (self.v & !0x03e0) | (y << 5);
+        }
+    }
+
+    /// Finds the sprites of the next line, whose patterns are then fetched during
+    /// dots 257-320. Nothing is drawn on the line after the pre-render line.
+    fn evaluate_sprite_line(&mut self) {
+        self.sprite_line = [SpritePixel::default(); 256];
+        self.line_sprite_count = 0;
+        self.line_has_sprite_zero = false;
+
+        if self.current_scanline == 261 || !self.mask.is_rendering_enabled() {
+            return;
         }
 
-        // dbg!(&secondary_oam_indexes);
-        for index in &secondary_oam_indexes {
-            let sprite = &sprites[*index];
+        let scanline = self.current_scanline;
+        let height = if self.control.drawing_mode() == SpriteDrawingMode::Draw8x16 {
+            16
+        } else {
+            8
+        };
+
+        for index in 0..64 {
+            if self.sprite_limit && self.line_sprite_count == 8 {
+                break;
+            }
 
-            for i in 0..8 {
-                if sprite.x.overflowing_add(i).1 {
-                    break;
+            let base = self.current_oam_address as usize + index * 4;
+            let y = self.oam_data[base % 256] as u32;
+            if y <= scanline && scanline < y + height {
+                for (offset, byte) in self.line_sprites[self.line_sprite_count]
+                    .iter_mut()
+                    .enumerate()
+                {
+                    *byte = self.oam_data[(base + offset) % 256];
                 }
+                self.line_sprite_count += 1;
+                self.line_has_sprite_zero |= index == 0;
+            }
+        }
+    }
 
-                let pixel_value = sprite_pixel_value(
-                    &sprite,
-                    |pattern_selection, tile, x, y| {
-                        self.read_pattern_value(pattern_selection, tile, x, y as u16)
-                    },
-                    self.current_scanline,
-                    i,
-                );
-
-                let target_buffer = if sprite.draw_priority == DrawPriority::Foreground {
-                    &mut self.foreground_sprite_buffer
-                } else {
-                    &mut self.background_sprite_buffer
-                };
+    /// One dot of the sprite pattern fetches. Each of the eight sprite slots gets
+    /// 8 dots, with the two pattern planes fetched on the 5th and 7th. Empty slots
+    /// fetch tile $FF.
+    fn step_sprite_fetch(&mut self) {
+        let slot = (self.current_dot as usize - 257) / 8;
+
+        match (self.current_dot - 1) % 8 {
+            4 => self.sprite_pattern_low = self.fetch_pattern(self.sprite_slot_address(slot)),
+            6 => {
+                let high = self.fetch_pattern(self.sprite_slot_address(slot) + 8);
+                if slot < self.line_sprite_count {
+                    self.draw_line_sprite(slot, self.sprite_pattern_low, high);
+                }
 
-                if let Some(value) = pixel_value {
-                    if value != 0
-                        && target_buffer[self.current_scanline as usize]
-                            [sprite.x as usize + i as usize]
-                            == 0xff
-                    {
-                        target_buffer[self.current_scanline as usize]
-                            [sprite.x as usize + i as usize] =
-                            palette[sprite.color_palette as usize][value as usize - 1];
+                // The sprites past the eighth have no slot of their own.
+                if slot == 7 {
+                    for index in 8..self.line_sprite_count {
+                        let address = self.line_sprite_address(index);
+                        let low = self.read_pattern_at_address(address);
+                        let high = self.read_pattern_at_address(address + 8);
+                        self.draw_line_sprite(index, low, high);
                     }
                 }
             }
+            _ => {}
         }
     }
 
-    fn toggle_sprite_0_hit_if_needed(&mut self) {
-        if !self.status.contains(PpuStatus::SPRITE_0_HIT) {
-            if !self
-                .mask
-                .contains(PpuMask::SHOW_BACKGROUND | PpuMask::SHOW_SPRITES)
-            {
-                return;
-            }
+    fn sprite_slot_address(&self, slot: usize) -> u16 {
+        if slot < self.line_sprite_count {
+            self.line_sprite_address(slot)
+        } else if self.control.drawing_mode() == SpriteDrawingMode::Draw8x16 {
+            pattern_address(PatternTableSelection::Right, 0xfe, 0)
+        } else {
+            pattern_address(self.control.sprite_pattern_table(), 0xff, 0)
+        }
+    }
 
-            let sprite_data = self.get_oam_sprite_data_at(0);
+    // Address of the low pattern plane of line sprite `index`, on the next line.
+    fn line_sprite_address(&self, index: usize) -> u16 {
+        let sprite = self.sprite_data(self.line_sprites[index]);
+        let (tile, row) = sprite_tile_row(&sprite, self.current_scanline - sprite.y as u32);
+
+        pattern_address(sprite.tile_pattern, tile, row as u16)
+    }
 
-            let sprite_y = sprite_data.y as u32 + 1;
-            let sprite_x = sprite_data.x;
+    // Lays out the pixels of line sprite `index` from its two pattern planes. A sprite
+    // earlier in OAM keeps the pixels it covers, even when it is behind the background.
+    fn draw_line_sprite(&mut self, index: usize, low_plane: u8, high_plane: u8) {
+        let sprite = self.sprite_data(self.line_sprites[index]);
 
-            if sprite_x == 255 {
-                return;
+        for i in 0..8 {
+            let (x, overflow) = sprite.x.overflowing_add(i);
+            if overflow {
+                break;
             }
 
-            let sprite_height_offset = if self.control.contains(PpuControl::SPRITE_8X16_MODE) {
-                15
-            } else {
-                7
-            };
+            let column = if sprite.flip_horizontal { 7 - i } else { i };
+            let pattern = pattern_bit(low_plane, high_plane, column);
 
-            // Check whether the scanline is in sprite's y range
-            if self.current_scanline < sprite_y
-                || self.current_scanline > sprite_y + sprite_height_offset
-            {
-                return;
+            let pixel = &mut self.sprite_line[x as usize];
+            if pattern != 0 && pixel.pattern == 0 {
+                *pixel = SpritePixel {
+                    pattern,
+                    palette: sprite.color_palette,
+                    behind_background: sprite.draw_priority == DrawPriority::Background,
+                    is_sprite_zero: index == 0 && self.line_has_sprite_zero,
+                };
             }
+        }
+    }
 
-            let include_leftmost_tile = self
-                .mask
-                .contains(PpuMask::SHOW_LEFTMOST_BACKGROUND | PpuMask::SHOW_LEFTMOST_SPRITES);
-
-            for i in 0..8 {
-                let (x, overflow) = sprite_x.overflowing_add(i);
+    /// Runs the sprite evaluation of dots 65-256 for the next line and returns the
+    /// dot at which it sets the overflow flag, if it does. Reading a sprite's Y takes
+    /// two dots, copying one in range into secondary OAM six more.
+    fn evaluate_sprite_overflow(&self) -> Option<u32> {
+        let scanline = self.current_scanline;
+        let height = if self.control.drawing_mode() == SpriteDrawingMode::Draw8x16 {
+            16
+        } else {
+            8
+        };
+        let in_range = |y: u8| y as u32 <= scanline && scanline < y as u32 + height;
+        let oam_byte = |sprite: usize, offset: usize| {
+            self.oam_data[(self.current_oam_address as usize + sprite * 4 + offset) % 256]
+        };
 
-                if overflow {
-                    break;
-                }
+        let mut dot = 65;
+        let mut sprite = 0;
+        let mut found = 0;
 
-                if x == 255 || (!include_leftmost_tile && x < 8) {
-                    continue;
-                }
+        while sprite < 64 && found < 8 {
+            if in_range(oam_byte(sprite, 0)) {
+                found += 1;
+                dot += 8;
+            } else {
+                dot += 2;
+            }
+            sprite += 1;
+        }
 
-                let pixel_value = sprite_pixel_value(
-                    &sprite_data,
-                    |pattern_selection, tile, x, y| {
-                        self.read_pattern_value(pattern_selection, tile, x, y as u16)
-                    },
-                    self.current_scanline,
-                    i,
-                );
-                if self.frame_buffer[self.current_scanline as usize][x as usize] != 0xff
-                    && pixel_value.unwrap_or(0) != 0
-                {
-                    self.status.insert(PpuStatus::SPRITE_0_HIT);
-                    return;
-                }
+        // Once secondary OAM is full the hardware increments the byte offset along
+        // with the sprite index, so it compares tile numbers, attributes and X
+        // positions as if they were Y coordinates.
+        let mut offset = 0;
+        while sprite < 64 {
+            if in_range(oam_byte(sprite, offset)) {
+                return Some(dot);
             }
+
+            sprite += 1;
+            offset = (offset + 1) % 4;
+            dot += 2;
         }
+
+        None
     }
 
     pub fn get_frame_buffer(&self) -> &[[u8; 256]; 240] {
         &self.frame_buffer
     }
 
-    pub fn get_foreground_sprite_buffer(&self) -> &[[u8; 256]; 240] {
-        &self.foreground_sprite_buffer
-    }
-
-    pub fn get_background_sprite_buffer(&self) -> &[[u8; 256]; 240] {
-        &self.background_sprite_buffer
-    }
-
-    pub fn generates_nmi_at_vblank(&self) -> bool {
-        self.control.contains(PpuControl::GENERATE_NMI_AT_VBLANK)
+    /// The PPU's NMI output, active while the vblank flag is set and NMIs are
+    /// enabled. The CPU triggers on it becoming active, so disabling and enabling
+    /// NMIs during vblank triggers another one.
+    pub fn nmi_line(&self) -> bool {
+        self.status.contains(PpuStatus::IN_VBLANK)
+            && self.control.contains(PpuControl::GENERATE_NMI_AT_VBLANK)
     }
 
     pub fn is_background_rendering_enabled(&self) -> bool {
@@ -883,83 +1109,172 @@ impl Ppu {
         self.mask.contains(PpuMask::SHOW_SPRITES)
     }
 
-    pub fn top_left_nametable_address(&self) -> u16 {
-        self.cartridge.borrow().mirroring().real_address(0x2000)
+    pub fn get_current_dot(&self) -> u32 {
+        self.current_dot
     }
+}
 
-    pub fn top_right_nametable_address(&self) -> u16 {
-        self.cartridge.borrow().mirroring().real_address(0x2400)
+fn save_screen_buffer(writer: &mut StateWriter, buffer: &[[u8; 256]; 240]) {
+    for row in buffer.iter() {
+        writer.write_bytes(row);
     }
+}
 
-    pub fn bottom_left_nametable_address(&self) -> u16 {
-        self.cartridge.borrow().mirroring().real_address(0x2800)
+fn load_screen_buffer(
+    reader: &mut StateReader,
+    buffer: &mut [[u8; 256]; 240],
+) -> Result<(), SaveStateError> {
+    for row in buffer.iter_mut() {
+        reader.read_bytes(row)?;
     }
 
-    pub fn bottom_right_nametable_address(&self) -> u16 {
-        self.cartridge.borrow().mirroring().real_address(0x2c00)
-    }
+    Ok(())
+}
 
-    pub fn triggers_scanline_tick(&self) -> bool {
-        if !self.is_background_rendering_enabled() && !self.is_sprite_rendering_enabled() {
-            return false;
+impl Snapshot for Ppu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.memory);
+        writer.write_bool(self.write_latch == WriteLatch::One);
+        writer.write_u8(self.status.bits());
+        writer.write_bool(self.vblank_suppressed);
+
+        writer.write_u8(self.current_oam_address);
+        writer.write_bytes(&self.oam_data);
+        writer.write_u8(self.control.bits());
+        writer.write_u8(self.mask.bits());
+
+        writer.write_u8(self.read_buffer);
+        writer.write_u8(self.io_latch);
+        for refreshed in self.io_latch_refreshed.iter() {
+            writer.write_u64(*refreshed);
         }
 
-        // if self.control
-        //     & (PpuControl::BACKGROUND_PATTERN_TABLE_FLAG | PpuControl::SPRITE_PATTERN_TABLE_FLAG)
-        //     == PpuControl::empty()
-        // {
-        //     return false;
-        // }
+        writer.write_u8(self.x);
+        writer.write_u16(self.t);
+        writer.write_u16(self.v);
+
+        writer.write_u32(self.current_scanline);
+        writer.write_u32(self.current_dot);
+        writer.write_u8(self.next_tile);
+        writer.write_u8(self.next_attribute);
+        writer.write_u8(self.next_pattern_low);
+        writer.write_u8(self.next_pattern_high);
+        writer.write_u16(self.pattern_shift_low);
+        writer.write_u16(self.pattern_shift_high);
+        writer.write_u16(self.attribute_shift_low);
+        writer.write_u16(self.attribute_shift_high);
+        writer.write_u64(self.cycle);
+        writer.write_bool(self.sprite_overflow_dot.is_some());
+        writer.write_u32(self.sprite_overflow_dot.unwrap_or(0));
+
+        for sprite in self.line_sprites.iter() {
+            writer.write_bytes(sprite);
+        }
+        writer.write_usize(self.line_sprite_count);
+        writer.write_bool(self.line_has_sprite_zero);
+        writer.write_u8(self.sprite_pattern_low);
+
+        save_screen_buffer(writer, &self.frame_buffer);
+        for pixel in self.sprite_line.iter() {
+            writer.write_u8(pixel.pattern);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.behind_background);
+            writer.write_bool(pixel.is_sprite_zero);
+        }
+    }
 
-        if self.control.contains(
-            PpuControl::BACKGROUND_PATTERN_TABLE_FLAG | PpuControl::SPRITE_PATTERN_TABLE_FLAG,
-        ) {
-            return false;
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError> {
+        reader.read_bytes(&mut self.memory)?;
+        self.write_latch = if reader.read_bool()? {
+            WriteLatch::One
+        } else {
+            WriteLatch::Zero
+        };
+        self.status =
+            PpuStatus::from_bits(reader.read_u8()?).ok_or(SaveStateError::InvalidValue)?;
+        self.vblank_suppressed = reader.read_bool()?;
+
+        self.current_oam_address = reader.read_u8()?;
+        reader.read_bytes(&mut self.oam_data)?;
+        self.control =
+            PpuControl::from_bits(reader.read_u8()?).ok_or(SaveStateError::InvalidValue)?;
+        self.mask = PpuMask::from_bits(reader.read_u8()?).ok_or(SaveStateError::InvalidValue)?;
+
+        self.read_buffer = reader.read_u8()?;
+        self.io_latch = reader.read_u8()?;
+        for refreshed in self.io_latch_refreshed.iter_mut() {
+            *refreshed = reader.read_u64()?;
         }
 
-        return true;
-    }
+        self.x = reader.read_u8()?;
+        self.t = reader.read_u16()?;
+        self.v = reader.read_u16()?;
+
+        self.current_scanline = reader.read_u32()?;
+        self.current_dot = reader.read_u32()?;
+        self.next_tile = reader.read_u8()?;
+        self.next_attribute = reader.read_u8()?;
+        self.next_pattern_low = reader.read_u8()?;
+        self.next_pattern_high = reader.read_u8()?;
+        self.pattern_shift_low = reader.read_u16()?;
+        self.pattern_shift_high = reader.read_u16()?;
+        self.attribute_shift_low = reader.read_u16()?;
+        self.attribute_shift_high = reader.read_u16()?;
+        self.cycle = reader.read_u64()?;
+        let has_overflow_dot = reader.read_bool()?;
+        let overflow_dot = reader.read_u32()?;
+        self.sprite_overflow_dot = if has_overflow_dot {
+            Some(overflow_dot)
+        } else {
+            None
+        };
 
-    fn scanline_tick_if_possible(&mut self) {
-        if self.triggers_scanline_tick() {
-            // println!("Scanline tick at {}", self.current_scanline);
-            self.cartridge.borrow_mut().scanline_tick();
+        for sprite in self.line_sprites.iter_mut() {
+            reader.read_bytes(sprite)?;
+        }
+        self.line_sprite_count = reader.read_usize()?;
+        if self.line_sprite_count > self.line_sprites.len() {
+            return Err(SaveStateError::InvalidValue);
+        }
+        self.line_has_sprite_zero = reader.read_bool()?;
+        self.sprite_pattern_low = reader.read_u8()?;
+
+        load_screen_buffer(reader, &mut self.frame_buffer)?;
+        for pixel in self.sprite_line.iter_mut() {
+            pixel.pattern = reader.read_u8()?;
+            pixel.palette = reader.read_u8()?;
+            pixel.behind_background = reader.read_bool()?;
+            pixel.is_sprite_zero = reader.read_bool()?;
         }
-    }
 
-    pub fn get_current_dot(&self) -> u32 {
-        self.current_dot
+        Ok(())
     }
 }
 
-fn sprite_pixel_value<F: Fn(PatternTableSelection, u8, u8, u8) -> u8>(
-    sprite_data: &SpriteData,
-    read_pattern: F,
-    y: u32,
-    x: u8,
-) -> Option<u8> {
-    let vertical_flip = sprite_data.flip_vertical;
-    let horizontal_flip = sprite_data.flip_horizontal;
+fn pattern_address(pattern_selection: PatternTableSelection, tile_number: u8, y: u16) -> u16 {
+    let mut address: u16 = 0;
+
+    if pattern_selection == PatternTableSelection::Right {
+        address += 0x1000;
+    }
 
-    let sprite_y = sprite_data.y as u32 + 1;
+    address + tile_number as u16 * 0x10 + y
+}
 
-    let sprite_height_offset = if sprite_data.drawing_mode == SpriteDrawingMode::Draw8x16 {
-        15
-    } else {
-        7
-    };
+fn pattern_bit(low_plane: u8, high_plane: u8, x: u8) -> u8 {
+    let shift = 7 - x;
 
-    // Check whether the scanline is in sprite's y range
-    if y < sprite_y || y > sprite_y + sprite_height_offset {
-        return None;
-    }
+    ((low_plane >> shift) & 1) + ((high_plane >> shift) & 1) * 2
+}
 
-    let mut sprite_fine_y = y - sprite_y;
+// The tile and the row within it holding line `sprite_fine_y` of a sprite, after flipping.
+fn sprite_tile_row(sprite_data: &SpriteData, sprite_fine_y: u32) -> (u8, u32) {
+    let mut sprite_fine_y = sprite_fine_y;
     let mut tile = sprite_data.tile_number;
 
-    if vertical_flip {
+    if sprite_data.flip_vertical {
         if sprite_data.drawing_mode == SpriteDrawingMode::Draw8x8 {
-            sprite_fine_y = sprite_height_offset - sprite_fine_y;
+            sprite_fine_y = 7 - sprite_fine_y;
         } else {
             // When flipping is on in 8x16 mode, the second tile is
             // above the first tile
@@ -975,11 +1290,196 @@ fn sprite_pixel_value<F: Fn(PatternTableSelection, u8, u8, u8) -> u8>(
         tile += 1;
         sprite_fine_y %= 8;
     }
-    // TODO: fix y type
-    Some(read_pattern(
-        sprite_data.tile_pattern,
-        tile,
-        if horizontal_flip { 7 - x } else { x },
-        sprite_fine_y.try_into().unwrap(),
-    ))
+
+    (tile, sprite_fine_y)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ines::nrom_cartridge;
+
+    // A PPU wired to an NROM cartridge whose pattern tables are `chr`.
+    fn ppu_with_chr(chr: &[u8]) -> Ppu {
+        let cartridge = nrom_cartridge(&[0; 0x4000], chr);
+        Ppu::new(Rc::new(RefCell::new(cartridge)))
+    }
+
+    // Sets row `row` of tile `tile` in the left pattern table, given as its eight
+    // pixel values from left to right.
+    fn set_tile_row(chr: &mut [u8], tile: u8, row: usize, pixels: [u8; 8]) {
+        let offset = tile as usize * 16 + row;
+        for (x, pixel) in pixels.iter().enumerate() {
+            chr[offset] |= (pixel & 1) << (7 - x);
+            chr[offset + 8] |= (pixel >> 1) << (7 - x);
+        }
+    }
+
+    #[test]
+    fn background_pipeline_applies_fine_x_scroll() {
+        // Every tile in the first row is different, each pixel of tile n is (n + x) % 4.
+        let mut chr = vec![0; 0x2000];
+        for tile in 0..32 {
+            let mut pixels = [0; 8];
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = (tile as usize + x) as u8 % 4;
+            }
+            set_tile_row(&mut chr, tile, 0, pixels);
+        }
+        let mut ppu = ppu_with_chr(&chr);
+
+        // The backdrop and palette 0 set to colors 0-3 so the frame buffer holds
+        // the raw pattern values.
+        ppu.write_address(0x20);
+        ppu.write_address(0x00);
+        for column in 0..32 {
+            ppu.write_data(column);
+        }
+        ppu.write_address(0x3f);
+        ppu.write_address(0x00);
+        for color in 0..4 {
+            ppu.write_data(color);
+        }
+
+        ppu.set_control(PpuControl::empty());
+        ppu.write_scroll(3);
+        ppu.write_scroll(0);
+        ppu.set_mask(PpuMask::SHOW_BACKGROUND | PpuMask::SHOW_LEFTMOST_BACKGROUND);
+
+        while ppu.get_current_scanline() != 1 {
+            ppu.step();
+        }
+
+        for x in 0..248 {
+            let scrolled_x = x + 3;
+            let expected = (scrolled_x / 8 + scrolled_x % 8) as u8 % 4;
+            assert_eq!(ppu.get_frame_buffer()[0][x], expected, "x = {}", x);
+        }
+    }
+
+    #[test]
+    fn composites_sprites_with_priority_and_clipping() {
+        let mut chr = vec![0; 0x2000];
+        set_tile_row(&mut chr, 1, 1, [0, 3, 3, 0, 0, 0, 3, 3]);
+        set_tile_row(&mut chr, 2, 0, [0, 3, 3, 3, 3, 3, 3, 3]);
+        let mut ppu = ppu_with_chr(&chr);
+
+        ppu.write_address(0x20);
+        ppu.write_address(0x00);
+        for _ in 0..32 {
+            ppu.write_data(1);
+        }
+
+        // Every palette entry holds its own index, except that writing $3F10
+        // also sets the backdrop to $10.
+        ppu.write_address(0x3f);
+        ppu.write_address(0x00);
+        for color in 0..32 {
+            ppu.write_data(color);
+        }
+
+        // Sprite 0 is behind the background, sprite 1 in front of it one pixel to
+        // the left, and sprite 2 sits in the clipped leftmost column.
+        ppu.set_oam_address(0);
+        for sprite in 0..64 {
+            let bytes = match sprite {
+                0 => [0, 2, 0x20, 16],
+                1 => [0, 2, 0x01, 15],
+                2 => [0, 2, 0x00, 0],
+                _ => [0xff; 4],
+            };
+            for byte in bytes.iter() {
+                ppu.write_oam_data(*byte);
+            }
+        }
+        ppu.set_oam_address(0);
+
+        ppu.set_control(PpuControl::empty());
+        ppu.write_scroll(0);
+        ppu.write_scroll(0);
+        ppu.set_mask(
+            PpuMask::SHOW_BACKGROUND | PpuMask::SHOW_SPRITES | PpuMask::SHOW_LEFTMOST_BACKGROUND,
+        );
+
+        while ppu.get_current_scanline() != 2 {
+            ppu.step();
+        }
+
+        let expected = [
+            0x10, 3, 3, 0x10, 0x10, 0x10, 3, 3, // sprite 2 clipped
+            0x10, 3, 3, 0x10, 0x10, 0x10, 3, 3, // background only
+            0x17, 3, 3, 0x13, 0x13, 0x13, 3, 3, // sprite 0 hides sprite 1
+        ];
+        assert_eq!(&ppu.get_frame_buffer()[1][..24], &expected[..]);
+        assert!(ppu.get_status().contains(PpuStatus::SPRITE_0_HIT));
+    }
+
+    #[test]
+    fn open_bus_reads_and_decay() {
+        let mut ppu = ppu_with_chr(&[0; 0x2000]);
+
+        // Writes to any register go through the I/O latch first.
+        ppu.write_io_latch(0xfe);
+        ppu.set_oam_address(0xfe);
+        assert_eq!(ppu.read_io_latch(), 0xfe);
+        assert_eq!(ppu.read_status() & 0x1f, 0x1e);
+
+        // The unused attribute bits don't exist in OAM.
+        ppu.set_oam_address(2);
+        ppu.write_io_latch(0xff);
+        ppu.write_oam_data(0xff);
+        ppu.set_oam_address(2);
+        assert_eq!(ppu.read_oam_data(), 0xe3);
+
+        // Palette reads only drive the low 6 bits.
+        ppu.write_address(0x3f);
+        ppu.write_address(0x00);
+        ppu.write_data(0x2a);
+        ppu.write_address(0x3f);
+        ppu.write_address(0x00);
+        ppu.write_io_latch(0xc0);
+        assert_eq!(ppu.read_data(), 0xea);
+
+        // A little over 600 ms later, nothing is left of the latch.
+        for _ in 0..341 * 262 * 37 {
+            ppu.step();
+        }
+        assert_eq!(ppu.read_io_latch(), 0);
+    }
+
+    #[test]
+    fn sprite_overflow_follows_diagonal_scan() {
+        let mut ppu = ppu_with_chr(&[0; 0x2000]);
+        ppu.set_mask(PpuMask::SHOW_BACKGROUND | PpuMask::SHOW_SPRITES);
+
+        // Eight sprites on line 20, then one whose tile number reads as an in-range
+        // Y because the ninth comparison looks at byte 1 of sprite 9.
+        ppu.set_oam_address(0);
+        for sprite in 0..64 {
+            let bytes = match sprite {
+                0..=7 => [20, 0, 0, 0],
+                9 => [0xff, 20, 0xff, 0xff],
+                _ => [0xff; 4],
+            };
+            for byte in bytes.iter() {
+                ppu.write_oam_data(*byte);
+            }
+        }
+        ppu.set_oam_address(0);
+
+        while !(ppu.get_current_scanline() == 20 && ppu.get_current_dot() == 65) {
+            ppu.step();
+        }
+        assert!(!ppu.get_status().contains(PpuStatus::SPRITE_OVERFLOW));
+
+        while ppu.get_current_dot() < 256 {
+            ppu.step();
+        }
+        assert!(ppu.get_status().contains(PpuStatus::SPRITE_OVERFLOW));
+
+        while ppu.get_current_scanline() != 0 {
+            ppu.step();
+        }
+        assert!(!ppu.get_status().contains(PpuStatus::SPRITE_OVERFLOW));
+    }
 }
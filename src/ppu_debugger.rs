@@ -38,29 +38,13 @@ impl<'a> PpuDebugger<'a> {
         canvas.set_draw_color(Color::WHITE);
         canvas.clear();
 
-        render_debug_nametable(
-            &mut self.top_left_nametable,
-            ppu,
-            ppu.top_left_nametable_address(),
-        );
+        render_debug_nametable(&mut self.top_left_nametable, ppu, 0x2000);
 
-        render_debug_nametable(
-            &mut self.top_right_nametable,
-            ppu,
-            ppu.top_right_nametable_address(),
-        );
+        render_debug_nametable(&mut self.top_right_nametable, ppu, 0x2400);
 
-        render_debug_nametable(
-            &mut self.bottom_left_nametable,
-            ppu,
-            ppu.bottom_left_nametable_address(),
-        );
+        render_debug_nametable(&mut self.bottom_left_nametable, ppu, 0x2800);
 
-        render_debug_nametable(
-            &mut self.bottom_right_nametable,
-            ppu,
-            ppu.bottom_right_nametable_address(),
-        );
+        render_debug_nametable(&mut self.bottom_right_nametable, ppu, 0x2c00);
 
         render_pattern_table(
             &mut self.left_pattern_table,
@@ -133,8 +117,6 @@ fn render_debug_nametable(texture: &mut Texture, ppu: &Ppu, nametable_address: u
     let mut color_buffer = [[0u8; 256]; 240];
 
     let raw_palette = ppu.get_color_palette();
-    let video_buffer = ppu.get_buffer();
-
     let current_nametable = nametable_address as usize;
     let current_attribute_table = current_nametable + 0x3c0;
 
@@ -142,13 +124,13 @@ fn render_debug_nametable(texture: &mut Texture, ppu: &Ppu, nametable_address: u
         for col in 0..32 {
             let nametable_address = row * 32 + col + current_nametable;
 
-            let nametable_value = video_buffer[nametable_address];
+            let nametable_value = ppu.peek_nametable(nametable_address as u16);
 
             let attribute_y = row / 4;
             let attribute_x = col / 4;
 
-            let attribute_value =
-                video_buffer[current_attribute_table + attribute_x + attribute_y * 8];
+            let attribute_value = ppu
+                .peek_nametable((current_attribute_table + attribute_x + attribute_y * 8) as u16);
 
             let top_left = attribute_value & 0b11;
             let top_right = attribute_value.bitand(0b1100 as u8) >> 2;
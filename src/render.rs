@@ -3,7 +3,7 @@ use std::{convert::TryInto, ops::BitAnd};
 use sdl2::{
     pixels::{Color, Palette, PixelFormatEnum},
     rect::Rect,
-    render::{BlendMode, Texture, WindowCanvas},
+    render::{Texture, WindowCanvas},
     surface::Surface,
     video::Window,
 };
@@ -95,47 +95,6 @@ fn palette_number(left: u8, right: u8, index: usize) -> u8 {
     }
 }
 
-fn create_debug_texture<T>(
-    texture_creator: &TextureCreator<T>,
-    pixel_format: PixelFormatEnum,
-) -> Texture<'_> {
-    let mut surface = Surface::new(256, 240, pixel_format).unwrap();
-
-    // tile grid
-    let color = sdl2::pixels::Color::RGBA(0x80, 0x80, 0x80, 0x80);
-
-    for row in (8..240).step_by(8) {
-        surface
-            .fill_rect(sdl2::rect::Rect::new(0, row, 256, 1), color)
-            .unwrap();
-    }
-
-    for col in (8..256).step_by(8) {
-        surface
-            .fill_rect(sdl2::rect::Rect::new(col, 0, 1, 240), color)
-            .unwrap();
-    }
-
-    // attribute grid
-    let color = sdl2::pixels::Color::RGBA(0xff, 0xff, 0xff, 0x90);
-
-    for row in (16..240).step_by(16) {
-        surface
-            .fill_rect(sdl2::rect::Rect::new(0, row, 256, 1), color)
-            .unwrap();
-    }
-
-    for col in (16..256).step_by(16) {
-        surface
-            .fill_rect(sdl2::rect::Rect::new(col, 0, 1, 240), color)
-            .unwrap();
-    }
-
-    texture_creator
-        .create_texture_from_surface(surface)
-        .unwrap()
-}
-
 const SCALE: u32 = 2;
 
 fn create_texture<'r>(
@@ -330,12 +289,7 @@ pub fn create_sdl_palette(color_palette: &[[u8; 3]]) -> Vec<Palette> {
 
 pub struct Renderer<'a> {
     canvas: Canvas<Window>,
-
-    texture_creator: &'a TextureCreator<WindowContext>,
-    debug_texture: Texture<'a>,
-    background_texture: Texture<'a>,
-    foreground_sprite_texture: Texture<'a>,
-    background_sprite_texture: Texture<'a>,
+    screen_texture: Texture<'a>,
 }
 
 impl<'a> Renderer<'a> {
@@ -343,142 +297,39 @@ impl<'a> Renderer<'a> {
         canvas: Canvas<Window>,
         texture_creator: &'a TextureCreator<WindowContext>,
     ) -> Renderer<'a> {
-        let debug_texture = create_debug_texture(&texture_creator, canvas.default_pixel_format());
-
-        let mut background_sprite_texture = texture_creator
-            .create_texture_target(None, 256, 240)
-            .unwrap();
-
-        background_sprite_texture.set_blend_mode(BlendMode::Blend);
-
-        let mut background_texture = texture_creator
-            .create_texture_target(None, 256, 240)
-            .unwrap();
-        background_texture.set_blend_mode(BlendMode::Blend);
-
-        let mut foreground_sprite_texture = texture_creator
-            .create_texture_target(canvas.default_pixel_format(), 256, 240)
+        let screen_texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::ARGB8888, 256, 240)
             .unwrap();
 
-        foreground_sprite_texture.set_blend_mode(sdl2::render::BlendMode::Blend);
-
         Renderer {
             canvas,
-            texture_creator,
-            debug_texture,
-            background_texture,
-            foreground_sprite_texture,
-            background_sprite_texture,
+            screen_texture,
         }
     }
 
+    /// Shows the PPU's finished frame. Priorities and clipping are already
+    /// applied, so this only maps palette indexes to colors.
     pub fn render(&mut self, ppu: &Ppu) {
-        self.canvas.set_draw_color(Color::GRAY);
-        self.canvas.clear();
-
-        let raw_palette = ppu.get_color_palette();
-
-        let background_sprite_texture = &mut self.background_sprite_texture;
-
-        let foreground_sprite_texture = &mut self.foreground_sprite_texture;
-        let mut foreground_pixels = [0u8; 256 * 240 * 4];
-        let frame = ppu.get_foreground_sprite_buffer();
-
-        for y in 0..240 {
-            for x in 0..256 {
-                let (r, g, b, a) = if frame[y][x] != 0xff {
-                    PALETTE[frame[y][x] as usize]
-                } else {
-                    (0, 0, 0, 0)
-                };
-
-                let start_index = (y * 256 + x) * 4;
-
-                foreground_pixels[start_index] = b;
-                foreground_pixels[start_index + 1] = g;
-                foreground_pixels[start_index + 2] = r;
-                foreground_pixels[start_index + 3] = a;
-            }
-        }
-
-        foreground_sprite_texture
-            .update(Rect::new(0, 0, 256, 240), &foreground_pixels, 256 * 4)
-            .unwrap();
-
-        let foreground_sprite_texture = &mut self.foreground_sprite_texture;
-        let mut background_pixels = [0u8; 256 * 240 * 4];
-        let frame = ppu.get_background_sprite_buffer();
-
-        for y in 0..240 {
-            for x in 0..256 {
-                let (r, g, b, a) = if frame[y][x] != 0xff {
-                    PALETTE[frame[y][x] as usize]
-                } else {
-                    (0, 0, 0, 0)
-                };
+        let mut pixels = [0u8; 256 * 240 * 4];
 
+        for (y, row) in ppu.get_frame_buffer().iter().enumerate() {
+            for (x, color) in row.iter().enumerate() {
+                let (r, g, b, a) = PALETTE[*color as usize];
                 let start_index = (y * 256 + x) * 4;
 
-                background_pixels[start_index] = b;
-                background_pixels[start_index + 1] = g;
-                background_pixels[start_index + 2] = r;
-                background_pixels[start_index + 3] = a;
-            }
-        }
-
-        background_sprite_texture
-            .update(Rect::new(0, 0, 256, 240), &background_pixels, 256 * 4)
-            .unwrap();
-
-        let debug_texture = &self.debug_texture;
-        let background_texture = &mut self.background_texture;
-
-        let mut background_tile_pixels = [0u8; 256 * 240 * 4];
-        let frame = ppu.get_frame_buffer();
-
-        if ppu.is_background_rendering_enabled() {
-            for y in 0..240 {
-                for x in 0..256 {
-                    // dbg!(&index, &frame[index]);
-                    let (r, g, b, a) = if frame[y][x] != 0xff {
-                        PALETTE[frame[y][x] as usize]
-                    } else {
-                        (0, 0, 0, 0)
-                    };
-
-                    let start_index = (y * 256 + x) * 4;
-
-                    background_tile_pixels[start_index] = b;
-                    background_tile_pixels[start_index + 1] = g;
-                    background_tile_pixels[start_index + 2] = r;
-                    background_tile_pixels[start_index + 3] = a;
-                }
+                pixels[start_index] = b;
+                pixels[start_index + 1] = g;
+                pixels[start_index + 2] = r;
+                pixels[start_index + 3] = a;
             }
         }
 
-        background_texture
-            .update(Rect::new(0, 0, 256, 240), &background_tile_pixels, 256 * 4)
+        self.screen_texture
+            .update(Rect::new(0, 0, 256, 240), &pixels, 256 * 4)
             .unwrap();
 
-        let (r, g, b, a) = PALETTE[raw_palette.background as usize];
-
-        self.canvas.set_draw_color(Color::RGBA(r, g, b, a));
         self.canvas.clear();
-
-        if ppu.is_sprite_rendering_enabled() {
-            self.canvas
-                .copy(&background_sprite_texture, None, None)
-                .unwrap();
-        }
-
-        self.canvas.copy(background_texture, None, None).unwrap();
-
-        if ppu.is_sprite_rendering_enabled() {
-            self.canvas
-                .copy(foreground_sprite_texture, None, None)
-                .unwrap();
-        }
-
+        self.canvas.copy(&self.screen_texture, None, None).unwrap();
         self.canvas.present();
     }
 }
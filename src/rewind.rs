@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+
+/// Ring buffer of machine snapshots for rewinding. Only the newest snapshot is
+/// kept whole, every older one is stored as the XOR against its successor with
+/// runs of zeros squeezed out, which is small since little changes between frames.
+pub struct RewindBuffer {
+    capacity: usize,
+    interval: u32,
+    frames_since_snapshot: u32,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+struct Delta {
+    length: usize,
+    data: Vec<u8>,
+}
+
+impl RewindBuffer {
+    /// Keeps up to `capacity` snapshots, one taken every `interval` frames.
+    pub fn new(capacity: usize, interval: u32) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            interval,
+            frames_since_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Called once per rendered frame. `snapshot` is only invoked on frames
+    /// where a snapshot is due.
+    pub fn frame_rendered<F: FnOnce() -> Vec<u8>>(&mut self, snapshot: F) {
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot >= self.interval {
+            self.frames_since_snapshot = 0;
+            self.push(snapshot());
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            self.deltas.push_back(Delta::between(&newest, &snapshot));
+        }
+
+        self.newest = Some(snapshot);
+
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Takes the newest snapshot out of the buffer, making the one before it the newest.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+
+        self.newest = self.deltas.pop_back().map(|delta| delta.apply(&newest));
+        self.frames_since_snapshot = 0;
+
+        Some(newest)
+    }
+
+    fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+}
+
+impl Delta {
+    // Encodes `older` relative to `newer` as (zero run, literal length, literals) triples.
+    fn between(older: &[u8], newer: &[u8]) -> Delta {
+        let mut data = Vec::new();
+        let mut literals = Vec::new();
+        let mut zero_run = 0;
+
+        for (index, byte) in older.iter().enumerate() {
+            let xor = byte ^ newer.get(index).unwrap_or(&0);
+
+            if xor == 0 {
+                if !literals.is_empty() {
+                    write_run(&mut data, zero_run, &literals);
+                    zero_run = 0;
+                    literals.clear();
+                }
+                zero_run += 1;
+            } else {
+                literals.push(xor);
+            }
+        }
+
+        if !literals.is_empty() {
+            write_run(&mut data, zero_run, &literals);
+        }
+
+        Delta {
+            length: older.len(),
+            data,
+        }
+    }
+
+    fn apply(&self, newer: &[u8]) -> Vec<u8> {
+        let mut older: Vec<u8> = (0..self.length)
+            .map(|index| *newer.get(index).unwrap_or(&0))
+            .collect();
+
+        let mut position = 0;
+        let mut index = 0;
+
+        while index < self.data.len() {
+            position += read_varint(&self.data, &mut index);
+            let literal_length = read_varint(&self.data, &mut index);
+
+            for byte in &self.data[index..index + literal_length] {
+                older[position] ^= byte;
+                position += 1;
+            }
+
+            index += literal_length;
+        }
+
+        older
+    }
+}
+
+fn write_run(data: &mut Vec<u8>, zero_run: usize, literals: &[u8]) {
+    write_varint(data, zero_run);
+    write_varint(data, literals.len());
+    data.extend_from_slice(literals);
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], index: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*index];
+        *index += 1;
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let newer: Vec<u8> = (0..1000).map(|value| (value % 251) as u8).collect();
+
+        let mut older = newer.clone();
+        older[0] = 0xff;
+        older[500..700].iter_mut().for_each(|byte| *byte = 0);
+        older[999] ^= 1;
+
+        let delta = Delta::between(&older, &newer);
+
+        assert!(delta.data.len() < 250);
+        assert_eq!(delta.apply(&newer), older);
+        assert_eq!(
+            Delta::between(&older[..10], &newer).apply(&newer),
+            &older[..10]
+        );
+        assert_eq!(
+            Delta::between(&older, &newer[..10]).apply(&newer[..10]),
+            older
+        );
+    }
+
+    #[test]
+    fn pops_snapshots_newest_first() {
+        let mut buffer = RewindBuffer::new(3, 2);
+
+        for frame in 0..10u8 {
+            buffer.frame_rendered(|| vec![frame; 300]);
+        }
+
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.pop(), Some(vec![9; 300]));
+        assert_eq!(buffer.pop(), Some(vec![7; 300]));
+        assert_eq!(buffer.pop(), Some(vec![5; 300]));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.len(), 0);
+    }
+}
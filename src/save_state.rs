@@ -0,0 +1,183 @@
+use std::convert::TryInto;
+
+const MAGIC: [u8; 4] = *b"MNSS";
+
+/// Bump this whenever the layout written by any `Snapshot` implementation changes.
+pub const SAVE_STATE_VERSION: u32 = 18;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(std::io::Error),
+    NotSaveState,
+    UnsupportedVersion(u32),
+    RomMismatch,
+    Truncated,
+    InvalidValue,
+}
+
+impl std::fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveStateError::Io(error) => write!(f, "{}", error),
+            SaveStateError::NotSaveState => write!(f, "not a save state file"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            SaveStateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::InvalidValue => write!(f, "save state is corrupted"),
+        }
+    }
+}
+
+impl From<std::io::Error> for SaveStateError {
+    fn from(error: std::io::Error) -> SaveStateError {
+        SaveStateError::Io(error)
+    }
+}
+
+/// Implemented by every piece of emulator state that goes into a save state.
+/// `load_state` must read back exactly what `save_state` wrote, in the same order.
+pub trait Snapshot {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), SaveStateError>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { bytes: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    /// Writes a byte slice whose length the reader already knows, e.g. a RAM array.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        let end = self.position + length;
+        if end > self.bytes.len() {
+            return Err(SaveStateError::Truncated);
+        }
+
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidValue),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, SaveStateError> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    /// Fills `buffer` completely, the counterpart of `StateWriter::write_bytes`.
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Result<(), SaveStateError> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+}
+
+/// FNV-1a hash of the PRG ROM, stored in the header so a state is not loaded into another game.
+pub fn rom_checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+pub fn write_header(writer: &mut StateWriter, rom_checksum: u32) {
+    writer.write_bytes(&MAGIC);
+    writer.write_u32(SAVE_STATE_VERSION);
+    writer.write_u32(rom_checksum);
+}
+
+pub fn read_header(reader: &mut StateReader, rom_checksum: u32) -> Result<(), SaveStateError> {
+    let mut magic = [0u8; 4];
+    reader
+        .read_bytes(&mut magic)
+        .map_err(|_| SaveStateError::NotSaveState)?;
+
+    if magic != MAGIC {
+        return Err(SaveStateError::NotSaveState);
+    }
+
+    let version = reader.read_u32()?;
+    if version != SAVE_STATE_VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    if reader.read_u32()? != rom_checksum {
+        return Err(SaveStateError::RomMismatch);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
+    AudioSubsystem,
+};
+
+use crate::apu::AudioSink;
+
+pub struct SdlAudioSink {
+    output_queue: AudioQueue<f32>,
+}
+
+impl SdlAudioSink {
+    pub fn new(audio_subsystem: &AudioSubsystem) -> Result<SdlAudioSink, String> {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),   // mono
+            samples: Some(2048), // default sample size
+        };
+
+        let output_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec)?;
+        output_queue.resume();
+
+        Ok(SdlAudioSink { output_queue })
+    }
+}
+
+impl AudioSink for SdlAudioSink {
+    fn queue(&mut self, samples: &[f32]) {
+        self.output_queue.queue(samples);
+    }
+}